@@ -1,13 +1,27 @@
-use crate::vpn::util::ConnectionProtocol;
+use crate::{
+	utils::Features,
+	vpn::{
+		split_tunnel::{SplitEntry, SplitMode},
+		util::ConnectionProtocol,
+	},
+};
 use structopt::StructOpt;
 
 mod configure;
 mod connect;
+mod disconnect;
 mod initialize;
+mod refresh;
+mod split_tunnel;
+mod status;
 
 pub use configure::configure;
-pub use connect::connect;
+pub use connect::{connect, reconnect};
+pub use disconnect::disconnect;
 pub use initialize::initialize;
+pub use refresh::refresh;
+pub use split_tunnel::split_tunnel;
+pub use status::status;
 
 /// An enum for all the cli's subcommands
 ///
@@ -28,10 +42,33 @@ pub enum CliOptions {
 	Configure,
 	/// Refresh OpenVPN configuration and server data.
 	Refresh,
+	/// Manage the networks that bypass the vpn.
+	SplitTunnel(SplitTunnelOptions),
 	/// Print some example commands.
 	Examples,
 }
 
+/// Each variant is a subcommand of the split-tunnel subcommand. Networks are written in CIDR notation, like `192.168.1.0/24`. A bare address means just that address. Domain names, like `git.example.com`, are resolved every time you connect.
+#[derive(StructOpt, Debug)]
+pub enum SplitTunnelOptions {
+	/// Route these networks around the vpn. Networks may not overlap each other.
+	Add {
+		/// Networks or domain names to add
+		#[structopt(required = true)]
+		networks: Vec<SplitEntry>,
+	},
+	/// Route these networks through the vpn again.
+	Remove {
+		/// Networks or domain names to remove, exactly as they were added
+		#[structopt(required = true)]
+		networks: Vec<SplitEntry>,
+	},
+	/// Print the networks and domains that bypass the vpn.
+	List,
+	/// Route every network through the vpn again.
+	Clear,
+}
+
 /// The struct contains the different variants of the connect subcommand (takes many subcommands as well.)
 #[derive(StructOpt, Debug)]
 pub struct Connect {
@@ -41,6 +78,29 @@ pub struct Connect {
 	/// Determine the protocol (UDP or TCP).
 	#[structopt(long, short)]
 	protocol: Option<ConnectionProtocol>,
+	/// See ServerConstraints for more info
+	#[structopt(flatten)]
+	constraints: ServerConstraints,
+	/// Use the split tunnel list for this connection, even if split tunneling is off. `exclude` routes the listed networks around the vpn, `include-only` routes only them through it.
+	#[structopt(long)]
+	split_mode: Option<SplitMode>,
+	/// Print the server and firewall changes that would be used, without connecting.
+	#[structopt(long)]
+	dry_run: bool,
+}
+
+/// Constraints on which servers a connect mode may pick. These apply on top of the mode's own constraints.
+#[derive(StructOpt, Debug, Default)]
+pub struct ServerConstraints {
+	/// Only use servers with all of these features: secure-core, tor, p2p, streaming, ipv6. Separate them with commas.
+	#[structopt(long, use_delimiter = true)]
+	feature: Vec<Features>,
+	/// Only use servers exiting in this country. 2 letter country code, like US or IN.
+	#[structopt(long)]
+	country: Option<String>,
+	/// Only use servers whose load is at most this percentage.
+	#[structopt(long)]
+	max_load: Option<u8>,
 }
 
 /// Each variant of this enum corresponds to a subcommand of the connect subcommand. Each variant has a corresponding submodule that handles that variant.
@@ -54,13 +114,20 @@ pub enum ConnectOptions {
 		cc: String,
 	},
 	/// Connect to the fastest Secure-Core server.
-	SecureCore,
-	/// Connect to the fastest torrent server.
+	SecureCore {
+		/// Optional 2 letter exit country code. Any exit country is used if this is missing.
+		cc: Option<String>,
+	},
+	/// Connect to the fastest torrent server. Short for `--feature p2p fastest`.
 	P2P,
-	/// Connect to the fastest Tor server.
+	/// Connect to the fastest Tor server. Short for `--feature tor fastest`.
 	Tor,
-	/// Select a random ProtonVPN server.
-	Random,
+	/// Select a random ProtonVPN server. Servers with less load are more likely to be picked.
+	Random {
+		/// Seed for the random number generator, so a selection can be reproduced.
+		#[structopt(long)]
+		seed: Option<u64>,
+	},
 	/// Select a specific server (must follow the name format)
 	Server {
 		/// Country code. Needs to match one the following python regexes
@@ -68,6 +135,8 @@ pub enum ConnectOptions {
 		///    - Example: UK-03/HK#5-Tor, for normal and tor servers
 		/// - Long: `^(((\w\w)(-|#)?([A-Z]{2}|FREE))(-|#)?(\d{1,3})-?(TOR)?)$`
 		///    - Example: IS-DE-01, for Secure-Core/Free/US Servers
+		///
+		/// Matching ignores case, so `is-de-01` and `IS-DE#1` are the same server.
 		server: String,
 	},
 }
@@ -1,16 +1,35 @@
 //! The functions in this module are expected to work. They have been tested by hand, but currently can't be tested programmatically because console doesn't have a testing functionality.
 
-use crate::{settings::Settings, vpn::util::UserConfig};
+use crate::{
+	settings::Settings,
+	vpn::{
+		firewall::{AutoDetect, FirewallBackend},
+		util::{KillSwitch, UserConfig},
+	},
+};
 use anyhow::Result;
 use console::Term;
 use dialoguer::{theme::ColorfulTheme, Select};
+use directories::ProjectDirs;
 
 /// Sets and saves new configuration settings, OVERWRITING the old options.
 ///
 /// Reads an int to determine what option is being set. Then calls the appropriate setter from [#Settings]. Does not save it to disk.
 ///
-pub fn configure(config: &mut UserConfig, terminal: &Term) -> Result<()> {
-	let options = ["Username", "Password", "Tier", "Protocol"];
+pub fn configure(config: &mut UserConfig, pdir: &ProjectDirs, terminal: &Term) -> Result<()> {
+	let options = [
+		"Username",
+		"Password",
+		"Tier",
+		"Protocol",
+		"Refresh interval",
+		"Kill switch",
+		"Split tunneling",
+		"Backend",
+		"Password command",
+		"Wireguard key",
+		"LAN access",
+	];
 	let opt = Select::with_theme(&ColorfulTheme::default())
 		.items(&options)
 		.interact_on(terminal)?;
@@ -20,7 +39,7 @@ pub fn configure(config: &mut UserConfig, terminal: &Term) -> Result<()> {
 			user_settings.set_username()?;
 		}
 		1 => {
-			user_settings.set_password()?;
+			user_settings.set_password(pdir)?;
 		}
 		2 => {
 			user_settings.set_tier()?;
@@ -28,6 +47,36 @@ pub fn configure(config: &mut UserConfig, terminal: &Term) -> Result<()> {
 		3 => {
 			user_settings.set_protocol()?;
 		}
+		4 => {
+			user_settings.set_server_cache_minutes()?;
+		}
+		5 => {
+			// Turning it off shouldn't leave an always on kill switch behind. The new setting is saved even if that fails
+			let was_off = config.killswitch == KillSwitch::Off;
+			if user_settings.set_killswitch()? == KillSwitch::Off && !was_off {
+				if let Err(e) = AutoDetect::default().flush() {
+					eprintln!(
+						"Couldn't remove the kill switch, so it may still block traffic: {:#}",
+						e
+					);
+				}
+			}
+		}
+		6 => {
+			user_settings.set_split_tunnel()?;
+		}
+		7 => {
+			user_settings.set_backend()?;
+		}
+		8 => {
+			user_settings.set_password_command()?;
+		}
+		9 => {
+			user_settings.confirm_wireguard_key(pdir)?;
+		}
+		10 => {
+			user_settings.set_allow_lan()?;
+		}
 		_ => {}
 	}
 	*config = user_settings.into_inner();
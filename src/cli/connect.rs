@@ -1,94 +1,934 @@
 use super::ConnectOptions::*;
 use crate::{
-	constants::{OVPN_FILE, OVPN_LOG},
-	utils::{config_path, get_servers},
-	vpn::{self, util::Config, VpnConnection},
+	constants::{RESOLV_CONF, SPLIT_TUNNEL_FILE},
+	utils::{config_path, get_client_config, get_servers, Features, LogicalServer},
+	vpn::{
+		self,
+		backend::{SystemBackend, VpnBackend},
+		command::{CommandRunner, SystemRunner},
+		dns::Dns,
+		firewall::{AutoDetect, FirewallBackend, KillSwitchRules, Recording},
+		ipv6::{host_has_ipv6, Ipv6Plan},
+		split_tunnel::{lookup_ipv4, Cidr, ResolvedDomain, SplitMode, SplitTunnelList},
+		util::{Backend, Config, KillSwitch, LastConnection, PlanTier, UserConfig},
+		wireguard::WG_PORT,
+	},
 };
-use anyhow::{anyhow, Context, Result};
+use anyhow::{anyhow, bail, Context, Result};
+use console::Term;
 use directories::ProjectDirs;
-use vpn::{connect as vpn_connect, util::ConnectionProtocol};
+use filter::ServerFilter;
+use name::find_server;
+use rand::{
+	distributions::{Distribution, WeightedIndex},
+	rngs::StdRng,
+	Rng, SeedableRng,
+};
+use std::{cmp::Ordering, io::Write};
+use vpn::{util::ConnectionProtocol, TunnelSettings};
 
 use super::Connect;
 
-/// Conncts to a server based on which variant of ConnectOptions Connect::connection_option is.
+mod filter;
+mod name;
+
+/// Conncts to a server based on which variant of ConnectOptions Connect::connection_option is. Does not save the config to disk.
+///
+/// Every mode except [Server](super::ConnectOptions::Server) and [Random](super::ConnectOptions::Random) picks the fastest server matching its [ServerFilter]. The mode only decides which constraints get added to the ones from the command line flags.
 pub fn connect(
 	flags: &Connect,
-	mut config: &mut Config,
+	config: &mut Config,
 	pdir: &ProjectDirs,
-) -> Result<VpnConnection> {
+	terminal: &mut Term,
+) -> Result<()> {
 	let Connect {
 		connection_option,
 		protocol,
+		constraints,
+		split_mode,
+		dry_run,
 	} = flags;
 
-	if let Server {
-		server: server_name,
-	} = connection_option
+	let protocol = protocol.unwrap_or(config.user.protocol);
+	let mut filter = ServerFilter::new(constraints)?;
+
+	match connection_option {
+		Fastest | Server { .. } | Random { .. } => {}
+		CountryCode { cc } => filter.restrict_country(cc)?,
+		SecureCore { cc } => {
+			filter.features |= Features::SECURE_CORE;
+			if let Some(cc) = cc {
+				filter.restrict_country(cc)?;
+			}
+		}
+		P2P => filter.features |= Features::P2P,
+		Tor => filter.features |= Features::TOR,
+	}
+
+	let servers = get_servers(config, pdir)?;
+	let tier = config.user.tier;
+	let server = match connection_option {
+		Server {
+			server: server_name,
+		} => pick_named(&servers, server_name, &filter, tier)?,
+		Random { seed } => {
+			let mut rng = match seed {
+				Some(seed) => StdRng::seed_from_u64(*seed),
+				None => StdRng::from_entropy(),
+			};
+			pick_random(&servers, &filter, tier, &mut rng)?
+		}
+		_ => pick_fastest(&servers, &filter, tier)?,
+	};
+
+	if *dry_run {
+		let mut firewall = Recording::default();
+		let (settings, ipv6, domains) = plan_session(
+			server,
+			protocol,
+			*split_mode,
+			&config.user,
+			&Host::probe(pdir),
+		)?;
+		prepare_firewall(&mut firewall, server, &settings, ipv6, &config.user)?;
+		writeln!(
+			terminal,
+			"Would connect to {} over {}",
+			server.name,
+			settings.transport()
+		)?;
+		for domain in domains {
+			writeln!(terminal, "Would split tunnel {}", domain)?;
+		}
+		for change in firewall.changes {
+			writeln!(terminal, "Would {}", change)?;
+		}
+		return Ok(());
+	}
+	connect_to(
+		server,
+		&protocol,
+		*split_mode,
+		config,
+		&mut Host::probe(pdir),
+		&mut AutoDetect::default(),
+		&mut SystemBackend::new(pdir),
+	)?;
+	print_connected(config, terminal)
+}
+
+/// Tears down the live session, if any, and connects to the server in [Config::last_connection] again. If that server is offline or gone, the fastest server like it is used instead.
+pub fn reconnect(config: &mut Config, pdir: &ProjectDirs, terminal: &mut Term) -> Result<()> {
+	if config.last_connection.is_none() {
+		bail!("There is no previous connection. Use `protonvpn connect` first");
+	}
+	let servers = get_servers(config, pdir)?;
+	reconnect_with(
+		&servers,
+		config,
+		&mut Host::probe(pdir),
+		&mut AutoDetect::default(),
+		&mut SystemBackend::new(pdir),
+	)?;
+	print_connected(config, terminal)
+}
+
+/// [reconnect] with the server list, host, firewall and vpn backend passed in
+fn reconnect_with<R: CommandRunner>(
+	servers: &[LogicalServer],
+	config: &mut Config,
+	host: &mut Host<R>,
+	firewall: &mut dyn FirewallBackend,
+	backend: &mut dyn VpnBackend,
+) -> Result<()> {
+	let last = config
+		.last_connection
+		.clone()
+		.context("There is no previous connection. Use `protonvpn connect` first")?;
+	let server = reconnect_target(servers, &last, config.user.tier)?;
+	connect_to(
+		server,
+		&last.protocol,
+		last.split_mode,
+		config,
+		host,
+		firewall,
+		backend,
+	)
+}
+
+fn print_connected(config: &Config, terminal: &mut dyn Write) -> Result<()> {
+	if let Some(info) = &config.connection_info {
+		writeln!(
+			terminal,
+			"Connected to {} over {}",
+			info.server_name,
+			info.transport()
+		)?;
+	}
+	Ok(())
+}
+
+/// The last used server if it is still usable. Otherwise the fastest server with the same exit country and features, never one that is less private. Fails if there is none.
+fn reconnect_target<'a>(
+	servers: &'a [LogicalServer],
+	last: &LastConnection,
+	tier: PlanTier,
+) -> Result<&'a LogicalServer> {
+	let (exit_country, features) = match servers.iter().find(|s| s.id == last.server_id) {
+		Some(previous) if previous.status == 1 && PlanTier::from(previous.tier) <= tier => {
+			return Ok(previous)
+		}
+		Some(previous) => (previous.exit_country.as_str(), previous.features),
+		None => match (&last.exit_country, last.features) {
+			(Some(exit_country), Some(features)) => (exit_country.as_str(), features),
+			_ => bail!(
+				"{} isn't listed anymore. Pick another server with `protonvpn connect`",
+				last.server_name
+			),
+		},
+	};
+
+	let mut filter = ServerFilter::default();
+	filter.features = features;
+	filter.restrict_country(exit_country)?;
+	pick_fastest(servers, &filter, tier)
+}
+
+/// Picks the fastest online server that the filter selects and the user's tier can use.
+fn pick_fastest<'a>(
+	servers: &'a [LogicalServer],
+	filter: &ServerFilter,
+	tier: PlanTier,
+) -> Result<&'a LogicalServer> {
+	fastest_server(candidates(servers, filter), tier)
+		.ok_or_else(|| no_server_error(servers, filter, tier))
+}
+
+/// Picks a random online server that the filter selects and the user's tier can use. A server's chance of being picked is inversely proportional to its load. Seeding `rng` makes the choice reproducible.
+fn pick_random<'a, R>(
+	servers: &'a [LogicalServer],
+	filter: &ServerFilter,
+	tier: PlanTier,
+	rng: &mut R,
+) -> Result<&'a LogicalServer>
+where
+	R: Rng,
+{
+	let choices: Vec<_> = candidates(servers, filter)
+		.filter(|s| PlanTier::from(s.tier) <= tier)
+		.collect();
+	if choices.is_empty() {
+		return Err(no_server_error(servers, filter, tier));
+	}
+
+	let weights = choices
+		.iter()
+		.map(|s| 1.0 / (f64::from(s.load.max(0)) + 1.0));
+	let distribution = WeightedIndex::new(weights).context("Couldn't weigh servers by load")?;
+	Ok(choices[distribution.sample(rng)])
+}
+
+/// Online servers that the filter selects, regardless of tier
+fn candidates<'a: 'f, 'f>(
+	servers: &'a [LogicalServer],
+	filter: &'f ServerFilter,
+) -> impl Iterator<Item = &'a LogicalServer> + 'f {
+	servers
+		.iter()
+		.filter(move |s| s.status == 1 && filter.selects(s))
+}
+
+/// Explains why no server could be picked. If a higher tier has matching servers, the error names it.
+fn no_server_error(
+	servers: &[LogicalServer],
+	filter: &ServerFilter,
+	tier: PlanTier,
+) -> anyhow::Error {
+	if let Some(needed) = candidates(servers, filter)
+		.map(|s| PlanTier::from(s.tier))
+		.min()
 	{
-		server(
-			server_name,
-			&protocol.unwrap_or(config.user.protocol),
-			&mut config,
-			pdir,
+		anyhow!(
+			"Your {} plan has no {}. The {} plan does.",
+			tier,
+			filter,
+			needed
 		)
+	} else if *filter == ServerFilter::default() {
+		anyhow!("There are no servers online")
 	} else {
-		Err(anyhow!(
-			"Operations besides connecting to a server not currently supported"
-		))
+		anyhow!(
+			"No online server meets all the constraints. There are no {}",
+			filter
+		)
 	}
 }
 
-/// Connect to the server specified on the command line
-fn server<S>(
-	server: S,
-	protocol: &ConnectionProtocol,
-	mut config: &mut Config,
-	pdir: &ProjectDirs,
-) -> Result<VpnConnection>
+/// Looks up the server named on the command line. See [name::ServerName] for the accepted formats.
+fn pick_named<'a>(
+	servers: &'a [LogicalServer],
+	name: &str,
+	filter: &ServerFilter,
+	tier: PlanTier,
+) -> Result<&'a LogicalServer> {
+	let server = find_server(servers, name)?;
+	if server.status != 1 {
+		return Err(anyhow!("{} is offline", server.name));
+	}
+	if PlanTier::from(server.tier) > tier {
+		return Err(anyhow!(
+			"{} needs the {} plan",
+			server.name,
+			PlanTier::from(server.tier)
+		));
+	}
+	if !filter.matches(server) {
+		return Err(anyhow!("{} isn't one of the {}", server.name, filter));
+	}
+	Ok(server)
+}
+
+/// Picks the server with the lowest score, using load to break ties. Servers above the user's tier are never picked.
+fn fastest_server<'a, I>(servers: I, tier: PlanTier) -> Option<&'a LogicalServer>
 where
-	S: AsRef<str>,
+	I: IntoIterator<Item = &'a LogicalServer>,
 {
-	let servers = get_servers(&mut config, pdir)?;
-	let server = servers
-		.iter()
-		.find(|s| s.name == server.as_ref())
-		.with_context(|| format!("Couldn't find server {}", server.as_ref()))
-		.unwrap();
-	let log_path = config_path(pdir, OVPN_LOG);
-	let config_path = config_path(pdir, OVPN_FILE);
-	vpn_connect(server, protocol, &config.user, &config_path, &log_path)
+	servers
+		.into_iter()
+		.filter(|s| PlanTier::from(s.tier) <= tier)
+		.min_by(|a, b| {
+			a.score
+				.partial_cmp(&b.score)
+				.unwrap_or(Ordering::Equal)
+				.then(a.load.cmp(&b.load))
+		})
+}
+
+/// What a session needs from this machine, besides the firewall and the vpn backend
+struct Host<'a, R> {
+	/// Where the split tunnel list and the cached client config live
+	pdir: &'a ProjectDirs,
+	/// Whether the host can reach the ipv6 internet
+	ipv6: bool,
+	/// Sets and reverts the dns servers for dns leak protection
+	dns: Dns<R>,
+}
+
+impl<'a> Host<'a, SystemRunner> {
+	fn probe(pdir: &'a ProjectDirs) -> Self {
+		Self {
+			pdir,
+			ipv6: host_has_ipv6(),
+			dns: Dns::default(),
+		}
+	}
+}
+
+/// Decides the openvpn settings for a connection to `server`, including how ipv6 is handled and which networks bypass the vpn. `split_mode` overrides the user's split tunnel setting. Domains in the split tunnel list are resolved here, and returned so the session can show them.
+///
+/// Include-only mode keeps the default route outside the vpn, so it needs a non-empty list, can't be combined with the kill switch and never blocks ipv6.
+fn plan_session<R>(
+	server: &LogicalServer,
+	protocol: ConnectionProtocol,
+	split_mode: Option<SplitMode>,
+	user: &UserConfig,
+	host: &Host<R>,
+) -> Result<(TunnelSettings, Ipv6Plan, Vec<ResolvedDomain>)> {
+	let mut ipv6 = Ipv6Plan::new(host.ipv6, server.features.contains(Features::IPV6));
+	let split_mode = split_mode.or_else(|| user.split_tunnel.then_some(SplitMode::Exclude));
+	let (split_tunnel, domains) = match split_mode {
+		Some(_) => {
+			SplitTunnelList::load(&config_path(host.pdir, SPLIT_TUNNEL_FILE))?.resolve(lookup_ipv4)
+		}
+		None => (vec![], vec![]),
+	};
+	if split_mode == Some(SplitMode::IncludeOnly) {
+		if split_tunnel.is_empty() {
+			bail!("Include-only split tunneling needs at least one network. Add some with `protonvpn split-tunnel add`");
+		}
+		if user.killswitch != KillSwitch::Off {
+			bail!("The kill switch blocks traffic outside the vpn, so it can't be used with include-only split tunneling");
+		}
+		ipv6.block_egress = false;
+	}
+	let (protocol, ports) = match user.backend {
+		Backend::OpenVpn => (
+			protocol,
+			get_client_config(host.pdir)
+				.openvpn_ports(protocol)
+				.to_vec(),
+		),
+		Backend::WireGuard => (ConnectionProtocol::UDP, vec![WG_PORT]),
+	};
+	let settings = TunnelSettings {
+		backend: user.backend,
+		protocol,
+		ports,
+		ipv6_disabled: ipv6.ipv6_disabled,
+		split_tunnel,
+		split_mode: split_mode.unwrap_or_default(),
+	};
+	Ok((settings, ipv6, domains))
+}
+
+/// Installs the kill switch for a connection to `server`, unless the user's is [KillSwitch::Off], and blocks ipv6 if the tunnel won't carry it
+fn prepare_firewall(
+	firewall: &mut dyn FirewallBackend,
+	server: &LogicalServer,
+	settings: &TunnelSettings,
+	ipv6: Ipv6Plan,
+	user: &UserConfig,
+) -> Result<()> {
+	if user.killswitch != KillSwitch::Off {
+		let bypass = match settings.split_mode {
+			SplitMode::Exclude => settings.split_tunnel.iter().map(Cidr::from).collect(),
+			SplitMode::IncludeOnly => vec![],
+		};
+		firewall.apply(
+			&KillSwitchRules::new(server, settings.protocol, &settings.ports)
+				.on_interface(settings.interface())
+				.allow_lan(user.allow_lan)
+				.bypass(bypass),
+		)?;
+	}
+	if ipv6.block_egress {
+		firewall.block_ipv6()?;
+	}
+	Ok(())
+}
+
+/// Connect to an already chosen server through `backend`. Any previous session is disconnected first. Once the tunnel is up, it is recorded in [Config::connection_info] and [Config::last_connection].
+///
+/// The kill switch and the ipv6 block go up before the tunnel does. If connecting fails, the ipv6 block is lifted, and the kill switch is only taken down again if it isn't [KillSwitch::AlwaysOn].
+fn connect_to<R: CommandRunner>(
+	server: &LogicalServer,
+	protocol: &ConnectionProtocol,
+	split_mode: Option<SplitMode>,
+	config: &mut Config,
+	host: &mut Host<R>,
+	firewall: &mut dyn FirewallBackend,
+	backend: &mut dyn VpnBackend,
+) -> Result<()> {
+	// Planning first resolves the split tunnel domains while the old session's dns still works
+	let (settings, ipv6, domains) =
+		plan_session(server, *protocol, split_mode, &config.user, host)?;
+	// The old session is only forgotten once it is torn down, so a failed teardown can still be retried with disconnect
+	if let Some(info) = &config.connection_info {
+		if let Some(kept) = host.dns.restore(&mut config.metadata)? {
+			eprintln!(
+				"Another program changed {} while connected, so it was left as is. The original was moved to {}",
+				RESOLV_CONF,
+				kept.display()
+			);
+		}
+		backend.down(info)?;
+		if info.ipv6_blocked {
+			firewall.unblock_ipv6()?;
+		}
+		config.connection_info = None;
+	}
+	prepare_firewall(firewall, server, &settings, ipv6, &config.user)?;
+
+	let started = backend
+		.prepare(server, &settings, &config.user)
+		.and_then(|()| backend.up(server, &settings))
+		.and_then(|info| {
+			let protected =
+				host.dns
+					.protect(&config.user, || backend.offered_dns(&info), &info.interface);
+			match protected {
+				Ok(dns) => Ok((info, dns)),
+				Err(e) => backend.down(&info).and(Err(e)),
+			}
+		});
+	let info = match started {
+		Ok((mut info, dns)) => {
+			if let Some((dns_server, change)) = dns {
+				info.dns_server = Some(dns_server);
+				change.record(&mut config.metadata);
+			}
+			info.ipv6_blocked = ipv6.block_egress;
+			info.split_domains = domains;
+			info
+		}
+		Err(e) => {
+			if ipv6.block_egress {
+				firewall.unblock_ipv6()?;
+			}
+			if config.user.killswitch == KillSwitch::On {
+				firewall.flush()?;
+			}
+			return Err(e);
+		}
+	};
+
+	config.connection_info = Some(info);
+	config.last_connection = Some(LastConnection {
+		server_id: server.id.clone(),
+		server_name: server.name.clone(),
+		protocol: *protocol,
+		split_mode,
+		exit_country: Some(server.exit_country.clone()),
+		features: Some(server.features),
+	});
+	Ok(())
 }
 
 #[cfg(test)]
 mod tests {
 
 	use chrono::Utc;
+	use tempfile::tempdir;
 	use vpn::util::UserConfig;
 
 	use super::*;
+	use crate::cli::{disconnect::disconnect_with, status::status_with};
 	use crate::utils::project_dirs;
-	use crate::vpn::util::MetaData;
+	use crate::vpn::{backend::Scripted, command::FakeRunner, dns::ResolvConf, util::MetaData};
+	use std::{fs::read_to_string, net::Ipv4Addr};
 
-	// #[test]
+	#[test]
+	#[ignore = "needs openvpn, root and network access"]
 	fn test_server() -> Result<()> {
 		let pdir = project_dirs();
 
 		let mut config = Config {
-			user: UserConfig::new("".into(), "".into()),
+			user: UserConfig::new("".into()),
 			connection_info: None,
+			last_connection: None,
 			metadata: MetaData {
-				resolvconf_hash: None,
 				last_api_pull: Utc::now(),
+				..Default::default()
 			},
 		};
 
-		let mut connection = server(
-			String::from("US-FREE#1"),
+		let mut backend = SystemBackend::new(&pdir);
+		let servers = get_servers(&mut config, &pdir)?;
+		let server = pick_named(
+			&servers,
+			"US-FREE#1",
+			&ServerFilter::default(),
+			config.user.tier,
+		)?;
+		connect_to(
+			server,
 			&ConnectionProtocol::UDP,
+			None,
+			&mut config,
+			&mut Host::probe(&pdir),
+			&mut Recording::default(),
+			&mut backend,
+		)?;
+		let info = config.connection_info.take().unwrap();
+		assert_eq!(info.server_name, "US-FREE#1");
+		backend.down(&info)
+	}
+
+	/// Goes through connect, status, reconnect and disconnect the way the cli does, with a tunnel that only exists in [Scripted] and a resolv.conf in a temp dir
+	#[test]
+	fn test_session() -> Result<()> {
+		let dir = tempdir()?;
+		let pdir = ProjectDirs::from_path(dir.path().into()).unwrap();
+		let resolv_conf = dir.path().join("resolv.conf");
+		let original = "nameserver 192.168.1.1\n";
+		std::fs::write(&resolv_conf, original)?;
+		let mut host = Host {
+			pdir: &pdir,
+			ipv6: false,
+			dns: Dns::new(ResolvConf::new(&resolv_conf), FakeRunner::default()),
+		};
+		let mut config = Config::default();
+		config.user.tier = PlanTier::Plus;
+		let mut servers = vec![
+			LogicalServer::mock("SE#1", 2, 1.0, 10),
+			LogicalServer::mock("SE#2", 2, 2.0, 10),
+		];
+		let mut backend = Scripted::default();
+		backend.dns = vec![Ipv4Addr::new(10, 8, 0, 1)];
+		let mut firewall = Recording::default();
+
+		connect_to(
+			&servers[0],
+			&ConnectionProtocol::TCP,
+			None,
+			&mut config,
+			&mut host,
+			&mut firewall,
+			&mut backend,
+		)?;
+		assert_eq!(backend.events, ["prepare SE#1", "up SE#1"]);
+		let info = config.connection_info.as_ref().unwrap();
+		assert_eq!(info.dns_server, Some(Ipv4Addr::new(10, 8, 0, 1)));
+		assert!(read_to_string(&resolv_conf)?.contains("nameserver 10.8.0.1\n"));
+		assert!(config.metadata.resolvconf_hash.is_some());
+		assert_eq!(
+			config
+				.last_connection
+				.as_ref()
+				.map(|l| l.server_id.as_str()),
+			Some("SE#1")
+		);
+		let mut output = vec![];
+		print_connected(&config, &mut output)?;
+		assert_eq!(String::from_utf8(output)?, "Connected to SE#1 over TCP\n");
+
+		let mut output = vec![];
+		status_with(&config, &mut output, &mut backend, || None)?;
+		let status = String::from_utf8(output)?;
+		assert!(status.contains("Status:    CONNECTED\n"), "{}", status);
+		assert!(status.contains("Tunnel IP: 10.8.0.2\n"), "{}", status);
+		assert!(status.contains("Received:  4.0 KiB\n"), "{}", status);
+
+		// The old session goes down before the fastest server like the offline one comes up, and the new one gets its own dns
+		servers[0].status = 0;
+		backend.events.clear();
+		backend.dns = vec![Ipv4Addr::new(10, 8, 0, 2)];
+		reconnect_with(
+			&servers,
+			&mut config,
+			&mut host,
+			&mut firewall,
+			&mut backend,
+		)?;
+		assert_eq!(backend.events, ["down SE#1", "prepare SE#2", "up SE#2"]);
+		assert_eq!(backend.running.len(), 1);
+		let info = config.connection_info.as_ref().unwrap();
+		assert_eq!(info.server_name, "SE#2");
+		assert_eq!(info.protocol, ConnectionProtocol::TCP);
+		assert_eq!(info.dns_server, Some(Ipv4Addr::new(10, 8, 0, 2)));
+		let protected = read_to_string(&resolv_conf)?;
+		assert!(protected.contains("nameserver 10.8.0.2\n"), "{}", protected);
+		assert!(!protected.contains("10.8.0.1"), "{}", protected);
+		assert!(config.metadata.resolvconf_hash.is_some());
+
+		// A session that doesn't go down stays recorded. Its dns was already reverted, because resolved needs the link to still exist for that
+		backend.fail_down = Some("openvpn is still running");
+		let err = reconnect_with(
+			&servers,
+			&mut config,
+			&mut host,
+			&mut firewall,
+			&mut backend,
+		)
+		.unwrap_err();
+		assert_eq!(err.to_string(), "openvpn is still running");
+		assert_eq!(config.connection_info.as_ref().unwrap().server_name, "SE#2");
+		assert_eq!(backend.running.len(), 1);
+		assert_eq!(read_to_string(&resolv_conf)?, original);
+		assert_eq!(config.metadata.resolvconf_hash, None);
+		backend.fail_down = None;
+		backend.events.clear();
+		let mut output = vec![];
+		disconnect_with(
+			&mut config,
+			&mut output,
+			&mut firewall,
+			&mut backend,
+			&mut host.dns,
+		)?;
+		assert_eq!(backend.events, ["down SE#2"]);
+		assert!(backend.running.is_empty());
+		assert!(config.connection_info.is_none());
+		assert_eq!(String::from_utf8(output)?, "Disconnected from SE#2\n");
+		assert_eq!(read_to_string(&resolv_conf)?, original);
+
+		// A tunnel that doesn't come up takes the kill switch with it
+		config.user.killswitch = KillSwitch::On;
+		backend.fail_up = Some("no route to host");
+		let mut firewall = Recording::default();
+		let err = reconnect_with(
+			&servers,
+			&mut config,
+			&mut host,
+			&mut firewall,
+			&mut backend,
+		)
+		.unwrap_err();
+		assert_eq!(err.to_string(), "no route to host");
+		assert!(config.connection_info.is_none());
+		assert!(firewall.changes[0].starts_with("enable kill switch"));
+		assert_eq!(firewall.changes.last().unwrap(), "disable kill switch");
+
+		// A tunnel that went down by itself makes status fail
+		config.user.killswitch = KillSwitch::Off;
+		backend.fail_up = None;
+		reconnect_with(
+			&servers,
 			&mut config,
-			&pdir,
+			&mut host,
+			&mut firewall,
+			&mut backend,
 		)?;
-		let res = connection.openvpn_process.wait()?;
-		Ok(assert!(res.success()))
+		backend.running.clear();
+		let err = status_with(&config, &mut vec![], &mut backend, || None).unwrap_err();
+		assert!(err.to_string().contains("ended unexpectedly"), "{}", err);
+		Ok(())
+	}
+
+	#[test]
+	fn test_prepare_firewall() -> Result<()> {
+		let server = LogicalServer::mock("CH#1", 2, 1.0, 10);
+		let settings = TunnelSettings {
+			backend: Backend::OpenVpn,
+			protocol: ConnectionProtocol::UDP,
+			ports: vec![1194],
+			ipv6_disabled: true,
+			split_tunnel: vec![],
+			split_mode: SplitMode::Exclude,
+		};
+		let no_block = Ipv6Plan::new(false, false);
+		let mut user = UserConfig::default();
+
+		let mut firewall = Recording::default();
+		prepare_firewall(&mut firewall, &server, &settings, no_block, &user)?;
+		assert!(firewall.changes.is_empty());
+
+		user.killswitch = KillSwitch::AlwaysOn;
+		prepare_firewall(
+			&mut firewall,
+			&server,
+			&settings,
+			Ipv6Plan::new(true, false),
+			&user,
+		)?;
+		assert_eq!(firewall.changes.len(), 2);
+		assert!(firewall.changes[0]
+			.starts_with("enable kill switch: allow loopback, proton0, dhcp and UDP to 127.0.0.1"));
+		assert_eq!(firewall.changes[1], "block outgoing ipv6");
+
+		let settings = TunnelSettings {
+			backend: Backend::WireGuard,
+			ports: vec![WG_PORT],
+			..settings
+		};
+		user.killswitch = KillSwitch::On;
+		user.allow_lan = true;
+		let mut firewall = Recording::default();
+		prepare_firewall(&mut firewall, &server, &settings, no_block, &user)?;
+		assert_eq!(
+			firewall.changes,
+			["enable kill switch: allow loopback, protonwg0, dhcp, the local network and UDP to 127.0.0.1 on port 51820, drop everything else"]
+		);
+
+		// Networks that split tunneling routes around the vpn stay reachable
+		let settings = TunnelSettings {
+			split_tunnel: vec!["10.1.0.0/16".parse::<Cidr>()?.into()],
+			..settings
+		};
+		let mut firewall = Recording::default();
+		prepare_firewall(&mut firewall, &server, &settings, no_block, &user)?;
+		assert!(
+			firewall.changes[0].contains("the local network, 10.1.0.0/16 outside the vpn and UDP")
+		);
+		Ok(())
+	}
+
+	fn in_country(cc: &str) -> ServerFilter {
+		let mut filter = ServerFilter::default();
+		filter.restrict_country(cc).unwrap();
+		filter
+	}
+
+	fn secure_core_in(cc: Option<&str>) -> ServerFilter {
+		let mut filter = ServerFilter::default();
+		filter.features = Features::SECURE_CORE;
+		if let Some(cc) = cc {
+			filter.restrict_country(cc).unwrap();
+		}
+		filter
+	}
+
+	#[test]
+	fn test_fastest_server() {
+		let servers = vec![
+			LogicalServer::mock("US-FREE#1", 0, 3.5, 20),
+			LogicalServer::mock("CH#1", 2, 1.2, 40),
+			LogicalServer::mock("NL#4", 1, 1.2, 10),
+			LogicalServer::mock("SE#2", 1, 2.0, 5),
+		];
+
+		let fastest = fastest_server(&servers, PlanTier::Plus).unwrap();
+		assert_eq!(fastest.name, "NL#4");
+
+		let fastest = fastest_server(&servers, PlanTier::Free).unwrap();
+		assert_eq!(fastest.name, "US-FREE#1");
+
+		assert!(fastest_server(&[], PlanTier::Plus).is_none());
+	}
+
+	#[test]
+	fn test_pick_fastest_in_country() {
+		let mut offline = LogicalServer::mock("CH#9", 1, 0.5, 1);
+		offline.status = 0;
+		let servers = vec![
+			LogicalServer::mock("CH#1", 2, 1.2, 40),
+			LogicalServer::mock("CH#2", 1, 2.4, 10),
+			offline,
+			LogicalServer::mock("IS#1", 2, 1.0, 10),
+			LogicalServer::mock("US-FREE#1", 0, 1.0, 10),
+		];
+
+		let server = pick_fastest(&servers, &in_country("CH"), PlanTier::Plus).unwrap();
+		assert_eq!(server.name, "CH#1");
+
+		let server = pick_fastest(&servers, &in_country("CH"), PlanTier::Basic).unwrap();
+		assert_eq!(server.name, "CH#2");
+
+		let err = pick_fastest(&servers, &in_country("IS"), PlanTier::Basic)
+			.unwrap_err()
+			.to_string();
+		assert!(err.contains("Plus"), "{}", err);
+
+		assert!(pick_fastest(&servers, &in_country("JP"), PlanTier::Plus).is_err());
+	}
+
+	#[test]
+	fn test_pick_fastest_secure_core() {
+		let secure_core = |name: &str, entry: &str, score: f64| {
+			let mut server = LogicalServer::mock(name, 2, score, 10);
+			server.entry_country = entry.into();
+			server.exit_country = name[3..5].into();
+			server.features = Features::SECURE_CORE;
+			server
+		};
+		let mut single_hop = LogicalServer::mock("DE#1", 2, 0.1, 10);
+		single_hop.features = Features::SECURE_CORE;
+		let servers = vec![
+			secure_core("CH-DE#1", "CH", 2.0),
+			secure_core("IS-US#1", "IS", 1.5),
+			single_hop,
+			LogicalServer::mock("US#1", 2, 0.2, 10),
+		];
+
+		let server = pick_fastest(&servers, &secure_core_in(None), PlanTier::Plus).unwrap();
+		assert_eq!(server.name, "IS-US#1");
+
+		let server = pick_fastest(&servers, &secure_core_in(Some("DE")), PlanTier::Plus).unwrap();
+		assert_eq!(server.name, "CH-DE#1");
+
+		let err = pick_fastest(&servers, &secure_core_in(None), PlanTier::Basic)
+			.unwrap_err()
+			.to_string();
+		assert!(err.contains("Plus"), "{}", err);
+
+		assert!(pick_fastest(&servers, &secure_core_in(Some("JP")), PlanTier::Plus).is_err());
+	}
+
+	#[test]
+	fn test_pick_fastest_with_features() {
+		let mut p2p = LogicalServer::mock("SE#1", 2, 2.0, 10);
+		p2p.features = Features::P2P;
+		let mut tor = LogicalServer::mock("SE#2", 2, 1.0, 10);
+		tor.features = Features::TOR;
+		let servers = vec![p2p, tor, LogicalServer::mock("SE#3", 2, 0.5, 10)];
+
+		let server = pick_fastest(&servers, &ServerFilter::default(), PlanTier::Plus).unwrap();
+		assert_eq!(server.name, "SE#3");
+
+		let mut filter = ServerFilter::default();
+		filter.features = Features::TOR;
+		let server = pick_fastest(&servers, &filter, PlanTier::Plus).unwrap();
+		assert_eq!(server.name, "SE#2");
+
+		filter.features = Features::TOR | Features::P2P;
+		let err = pick_fastest(&servers, &filter, PlanTier::Plus)
+			.unwrap_err()
+			.to_string();
+		assert!(err.contains("constraints"), "{}", err);
+	}
+
+	#[test]
+	fn test_pick_random() {
+		let mut offline = LogicalServer::mock("SE#4", 2, 1.0, 0);
+		offline.status = 0;
+		let servers = vec![
+			LogicalServer::mock("SE#1", 2, 1.0, 0),
+			LogicalServer::mock("SE#2", 2, 1.0, 99),
+			LogicalServer::mock("CH#1", 2, 1.0, 0),
+			LogicalServer::mock("SE#3", 3, 1.0, 0),
+			offline,
+		];
+		let filter = in_country("SE");
+
+		let pick = |seed| {
+			let mut rng = StdRng::seed_from_u64(seed);
+			pick_random(&servers, &filter, PlanTier::Plus, &mut rng)
+				.unwrap()
+				.name
+				.clone()
+		};
+		assert_eq!(pick(7), pick(7));
+
+		let mut rng = StdRng::seed_from_u64(42);
+		let mut idle = 0;
+		for _ in 0..1000 {
+			let server = pick_random(&servers, &filter, PlanTier::Plus, &mut rng).unwrap();
+			assert!(server.name == "SE#1" || server.name == "SE#2");
+			if server.name == "SE#1" {
+				idle += 1;
+			}
+		}
+		assert!(idle > 900, "{}", idle);
+
+		let mut rng = StdRng::seed_from_u64(42);
+		assert!(pick_random(&servers, &in_country("JP"), PlanTier::Plus, &mut rng).is_err());
+	}
+
+	#[test]
+	fn test_reconnect_target() {
+		let mut offline = LogicalServer::mock("SE#1", 2, 0.5, 10);
+		offline.status = 0;
+		offline.features = Features::P2P;
+		let mut p2p = LogicalServer::mock("SE#2", 2, 2.0, 10);
+		p2p.features = Features::P2P;
+		let servers = vec![
+			offline,
+			p2p,
+			LogicalServer::mock("SE#3", 2, 1.0, 10),
+			LogicalServer::mock("CH#1", 2, 0.1, 10),
+		];
+		let last = |name: &str| LastConnection {
+			server_id: name.into(),
+			server_name: name.into(),
+			protocol: ConnectionProtocol::TCP,
+			split_mode: None,
+			exit_country: Some(name[..2].into()),
+			features: Some(Features::empty()),
+		};
+
+		let server = reconnect_target(&servers, &last("SE#3"), PlanTier::Plus).unwrap();
+		assert_eq!(server.name, "SE#3");
+
+		let server = reconnect_target(&servers, &last("SE#1"), PlanTier::Plus).unwrap();
+		assert_eq!(server.name, "SE#2");
+
+		let server = reconnect_target(&servers, &last("SE#3"), PlanTier::Basic);
+		assert!(server.is_err());
+
+		// A server that isn't listed anymore is replaced by one like it, never by any server at all
+		let server = reconnect_target(&servers, &last("SE#9"), PlanTier::Plus).unwrap();
+		assert_eq!(server.name, "SE#3");
+		let gone_p2p = LastConnection {
+			features: Some(Features::P2P),
+			..last("SE#9")
+		};
+		let server = reconnect_target(&servers, &gone_p2p, PlanTier::Plus).unwrap();
+		assert_eq!(server.name, "SE#2");
+		assert!(reconnect_target(&servers, &last("DE#1"), PlanTier::Plus).is_err());
+		let secure_core = LastConnection {
+			features: Some(Features::SECURE_CORE),
+			..last("CH#2")
+		};
+		assert!(reconnect_target(&servers, &secure_core, PlanTier::Plus).is_err());
+
+		// Older configs don't know what the server was like
+		let old = LastConnection {
+			exit_country: None,
+			features: None,
+			..last("DE#1")
+		};
+		let err = reconnect_target(&servers, &old, PlanTier::Plus).unwrap_err();
+		assert!(err.to_string().contains("isn't listed anymore"), "{}", err);
 	}
 }
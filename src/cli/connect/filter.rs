@@ -0,0 +1,194 @@
+//! Narrowing down the server list. Every connect mode builds a [ServerFilter] from the [ServerConstraints] flags, then adds its own constraints on top.
+
+use crate::{
+	cli::ServerConstraints,
+	constants::COUNTRY_CODES,
+	utils::{country_name, Features, LogicalServer},
+};
+use anyhow::{anyhow, Result};
+use std::{
+	cmp::Ordering,
+	fmt::{self, Display},
+};
+use strsim::jaro_winkler;
+
+/// Constraints a server has to meet to be connected to. An empty filter matches every server.
+#[derive(Debug, Default, PartialEq)]
+pub(crate) struct ServerFilter {
+	/// Servers must have all of these features
+	pub(crate) features: Features,
+	/// Uppercase exit country code
+	country: Option<String>,
+	/// Maximum load, as a percentage
+	max_load: Option<u8>,
+}
+
+impl ServerFilter {
+	/// Validates the command line flags
+	pub(crate) fn new(constraints: &ServerConstraints) -> Result<Self> {
+		let mut filter = Self {
+			features: constraints.feature.iter().copied().collect(),
+			max_load: constraints.max_load,
+			..Default::default()
+		};
+		if let Some(cc) = &constraints.country {
+			filter.restrict_country(cc)?;
+		}
+		Ok(filter)
+	}
+
+	/// Only allow servers exiting in `cc`. Fails if `cc` isn't a known country, or another country was already chosen.
+	pub(crate) fn restrict_country(&mut self, cc: &str) -> Result<()> {
+		let cc = validate_country_code(cc)?;
+		match &self.country {
+			Some(country) if *country != cc => Err(anyhow!(
+				"Can't connect to both {} and {}",
+				country_name(country),
+				country_name(&cc)
+			)),
+			_ => {
+				self.country = Some(cc);
+				Ok(())
+			}
+		}
+	}
+
+	/// Whether `server` meets every constraint
+	pub(crate) fn matches(&self, server: &LogicalServer) -> bool {
+		server.features.contains(self.features)
+			&& (!self.features.contains(Features::SECURE_CORE) || server.is_secure_core())
+			&& self
+				.country
+				.as_ref()
+				.is_none_or(|cc| server.exit_country.eq_ignore_ascii_case(cc))
+			&& self
+				.max_load
+				.is_none_or(|max_load| server.load <= max_load.into())
+	}
+
+	/// Like [ServerFilter::matches], but also leaves out Secure-Core and Tor servers unless they were asked for. Used when the app picks the server instead of the user.
+	pub(crate) fn selects(&self, server: &LogicalServer) -> bool {
+		let special = Features::SECURE_CORE | Features::TOR;
+		self.matches(server) && (server.features & (special - self.features)).is_empty()
+	}
+}
+
+/// Describes the servers the filter matches, like `P2P servers in Sweden with at most 50% load`
+impl Display for ServerFilter {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		if !self.features.is_empty() {
+			write!(f, "{} ", self.features)?;
+		}
+		write!(f, "servers")?;
+		if let Some(cc) = &self.country {
+			write!(f, " in {}", country_name(cc))?;
+		}
+		if let Some(max_load) = self.max_load {
+			write!(f, " with at most {}% load", max_load)?;
+		}
+		Ok(())
+	}
+}
+
+/// Checks `cc` against [COUNTRY_CODES], returning it uppercased. Unknown codes produce an error suggesting the closest known countries.
+pub(crate) fn validate_country_code(cc: &str) -> Result<String> {
+	let upper = cc.trim().to_ascii_uppercase();
+	if COUNTRY_CODES.contains_key(&upper) {
+		return Ok(upper);
+	}
+
+	let lower = cc.trim().to_lowercase();
+	let mut suggestions: Vec<(f64, &String, &String)> = COUNTRY_CODES
+		.iter()
+		.map(|(code, name)| {
+			let similarity = jaro_winkler(&lower, &code.to_lowercase())
+				.max(jaro_winkler(&lower, &name.to_lowercase()));
+			(similarity, code, name)
+		})
+		.filter(|(similarity, _, _)| *similarity >= 0.8)
+		.collect();
+	suggestions.sort_by(|a, b| {
+		b.0.partial_cmp(&a.0)
+			.unwrap_or(Ordering::Equal)
+			.then(a.1.cmp(b.1))
+	});
+
+	if suggestions.is_empty() {
+		Err(anyhow!("Unknown country code {}", cc))
+	} else {
+		let suggestions = suggestions
+			.iter()
+			.take(3)
+			.map(|(_, code, name)| format!("{} ({})", code, name))
+			.collect::<Vec<_>>()
+			.join(", ");
+		Err(anyhow!(
+			"Unknown country code {}. Did you mean {}?",
+			cc,
+			suggestions
+		))
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn test_validate_country_code() {
+		assert_eq!(validate_country_code("ch").unwrap(), "CH");
+		assert_eq!(validate_country_code("US").unwrap(), "US");
+
+		let err = validate_country_code("germany").unwrap_err().to_string();
+		assert!(err.contains("Did you mean DE (Germany)"), "{}", err);
+
+		assert!(validate_country_code("12345").is_err());
+	}
+
+	#[test]
+	fn test_filter_new() -> Result<()> {
+		let constraints = ServerConstraints {
+			feature: vec![Features::P2P, Features::TOR],
+			country: Some("se".into()),
+			max_load: Some(50),
+		};
+		let mut filter = ServerFilter::new(&constraints)?;
+		assert_eq!(filter.features, Features::P2P | Features::TOR);
+		assert_eq!(
+			filter.to_string(),
+			"Tor and P2P servers in Sweden with at most 50% load"
+		);
+
+		filter.restrict_country("SE")?;
+		assert!(filter.restrict_country("CH").is_err());
+		Ok(())
+	}
+
+	#[test]
+	fn test_filter_matches() {
+		let mut p2p = LogicalServer::mock("SE#1", 2, 1.0, 30);
+		p2p.features = Features::P2P;
+		let mut tor = LogicalServer::mock("SE#2", 2, 1.0, 30);
+		tor.features = Features::P2P | Features::TOR;
+		let busy = LogicalServer::mock("SE#3", 2, 1.0, 90);
+
+		let filter = ServerFilter {
+			max_load: Some(50),
+			..Default::default()
+		};
+		assert!(filter.selects(&p2p));
+		assert!(!filter.selects(&tor));
+		assert!(filter.matches(&tor));
+		assert!(!filter.matches(&busy));
+
+		let filter = ServerFilter {
+			features: Features::P2P,
+			country: Some("SE".into()),
+			..Default::default()
+		};
+		assert!(filter.selects(&p2p));
+		assert!(!filter.selects(&tor));
+		assert!(!filter.matches(&busy));
+		assert!(!filter.matches(&LogicalServer::mock("CH#1", 2, 1.0, 30)));
+	}
+}
@@ -0,0 +1,230 @@
+//! Parsing the names users type for [Server](crate::cli::ConnectOptions::Server). ProtonVPN's own format is `CC#N`, `CC-XX#N` or `CC-FREE#N`, with an optional `-TOR` suffix, but the separators, case and leading zeros are all optional on the command line.
+
+use crate::utils::LogicalServer;
+use anyhow::{anyhow, Result};
+use std::{
+	cmp::Ordering,
+	fmt::{self, Display},
+	iter::Peekable,
+	str::{Chars, FromStr},
+};
+use strsim::jaro_winkler;
+
+/// The parts of a server name. Two names that differ only in formatting parse to equal values.
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) struct ServerName {
+	/// Entry country for Secure-Core servers, exit country for the rest
+	country: String,
+	/// Exit country for Secure-Core servers, a US state, or `FREE`
+	region: Option<String>,
+	number: u16,
+	tor: bool,
+}
+
+/// Accepts the short form `^((\w\w)(-|#)?(\d{1,3})-?(TOR)?)$` and the long form `^(((\w\w)(-|#)?([A-Z]{2}|FREE))(-|#)?(\d{1,3})-?(TOR)?)$`, ignoring case.
+impl FromStr for ServerName {
+	type Err = String;
+	fn from_str(s: &str) -> Result<Self, Self::Err> {
+		let invalid = || {
+			format!(
+				"{} isn't a valid server name. Server names look like UK#3, HK#5-TOR or IS-DE#1",
+				s
+			)
+		};
+		let upper = s.trim().to_ascii_uppercase();
+		let mut chars = upper.chars().peekable();
+
+		let country = take_while(&mut chars, |c| c.is_ascii_alphabetic(), 2);
+		if country.len() != 2 {
+			return Err(invalid());
+		}
+		skip_separator(&mut chars);
+
+		let region = take_while(&mut chars, |c| c.is_ascii_alphabetic(), 4);
+		let region = match region.len() {
+			0 => None,
+			2 => Some(region),
+			4 if region == "FREE" => Some(region),
+			_ => return Err(invalid()),
+		};
+		if region.is_some() {
+			skip_separator(&mut chars);
+		}
+
+		let number = take_while(&mut chars, |c| c.is_ascii_digit(), 3);
+		let number = number.parse().map_err(|_| invalid())?;
+
+		if chars.peek() == Some(&'-') {
+			chars.next();
+		}
+		let suffix: String = chars.collect();
+		let tor = match suffix.as_str() {
+			"" => false,
+			"TOR" => true,
+			_ => return Err(invalid()),
+		};
+
+		Ok(Self {
+			country,
+			region,
+			number,
+			tor,
+		})
+	}
+}
+
+/// The canonical form, like `IS-DE#1` or `HK#5-TOR`
+impl Display for ServerName {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		write!(f, "{}", self.country)?;
+		if let Some(region) = &self.region {
+			write!(f, "-{}", region)?;
+		}
+		write!(f, "#{}", self.number)?;
+		if self.tor {
+			write!(f, "-TOR")?;
+		}
+		Ok(())
+	}
+}
+
+fn take_while<P>(chars: &mut Peekable<Chars>, predicate: P, max: usize) -> String
+where
+	P: Fn(char) -> bool,
+{
+	let mut taken = String::new();
+	while taken.len() < max {
+		match chars.peek() {
+			Some(&c) if predicate(c) => {
+				taken.push(c);
+				chars.next();
+			}
+			_ => break,
+		}
+	}
+	taken
+}
+
+fn skip_separator(chars: &mut Peekable<Chars>) {
+	if let Some('-') | Some('#') = chars.peek() {
+		chars.next();
+	}
+}
+
+/// Finds the server the user meant by `name`. If there isn't one, the error lists the closest server names.
+pub(crate) fn find_server<'a>(
+	servers: &'a [LogicalServer],
+	name: &str,
+) -> Result<&'a LogicalServer> {
+	let parsed = name.parse::<ServerName>();
+	let found = servers.iter().find(|s| match &parsed {
+		Ok(parsed) => s.name.parse().as_ref() == Ok(parsed),
+		Err(_) => s.name.eq_ignore_ascii_case(name.trim()),
+	});
+	if let Some(server) = found {
+		return Ok(server);
+	}
+
+	let wanted = match &parsed {
+		Ok(parsed) => parsed.to_string(),
+		Err(_) => name.trim().to_ascii_uppercase(),
+	};
+	let closest = closest_names(servers, &wanted, 5);
+	let reason = match parsed {
+		Ok(_) => format!("Couldn't find server {}", name),
+		Err(reason) => reason,
+	};
+	if closest.is_empty() {
+		Err(anyhow!(reason))
+	} else {
+		Err(anyhow!(
+			"{}. The closest servers are {}",
+			reason,
+			closest.join(", ")
+		))
+	}
+}
+
+/// Up to `count` server names, most similar to `wanted` first
+fn closest_names<'a>(servers: &'a [LogicalServer], wanted: &str, count: usize) -> Vec<&'a str> {
+	let mut scored: Vec<_> = servers
+		.iter()
+		.map(|s| {
+			(
+				jaro_winkler(wanted, &s.name.to_ascii_uppercase()),
+				s.name.as_str(),
+			)
+		})
+		.collect();
+	scored.sort_by(|a, b| {
+		b.0.partial_cmp(&a.0)
+			.unwrap_or(Ordering::Equal)
+			.then(a.1.cmp(b.1))
+	});
+	scored
+		.into_iter()
+		.take(count)
+		.map(|(_, name)| name)
+		.collect()
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn canonical(name: &str) -> String {
+		name.parse::<ServerName>().unwrap().to_string()
+	}
+
+	#[test]
+	fn test_parse_short() {
+		assert_eq!(canonical("UK-03"), "UK#3");
+		assert_eq!(canonical("uk#3"), "UK#3");
+		assert_eq!(canonical("UK3"), "UK#3");
+		assert_eq!(canonical("HK#5-Tor"), "HK#5-TOR");
+		assert_eq!(canonical("hk5tor"), "HK#5-TOR");
+	}
+
+	#[test]
+	fn test_parse_long() {
+		assert_eq!(canonical("IS-DE-01"), "IS-DE#1");
+		assert_eq!(canonical("is-de#1"), "IS-DE#1");
+		assert_eq!(canonical("US-FREE#12"), "US-FREE#12");
+		assert_eq!(canonical("usfree012"), "US-FREE#12");
+		assert_eq!(canonical("US-NY#100-TOR"), "US-NY#100-TOR");
+	}
+
+	#[test]
+	fn test_parse_invalid() {
+		for name in ["", "U#1", "UK", "UK#", "UK#1234", "UK-ABC#1", "UK#1-VPN"].iter() {
+			assert!(name.parse::<ServerName>().is_err(), "{}", name);
+		}
+	}
+
+	#[test]
+	fn test_find_server() {
+		let servers = vec![
+			LogicalServer::mock("US-FREE#1", 0, 1.0, 10),
+			LogicalServer::mock("US-FREE#2", 0, 1.0, 10),
+			LogicalServer::mock("IS-DE#1", 2, 1.0, 10),
+			LogicalServer::mock("HK#5-TOR", 2, 1.0, 10),
+		];
+
+		assert_eq!(
+			find_server(&servers, "us-free-01").unwrap().name,
+			"US-FREE#1"
+		);
+		assert_eq!(find_server(&servers, "ISDE1").unwrap().name, "IS-DE#1");
+		assert_eq!(find_server(&servers, "hk#05-tor").unwrap().name, "HK#5-TOR");
+
+		let err = find_server(&servers, "US-FREE#3").unwrap_err().to_string();
+		assert!(
+			err.contains("closest servers are US-FREE#1, US-FREE#2"),
+			"{}",
+			err
+		);
+
+		let err = find_server(&servers, "nonsense").unwrap_err().to_string();
+		assert!(err.contains("isn't a valid server name"), "{}", err);
+	}
+}
@@ -0,0 +1,65 @@
+use crate::{
+	constants::RESOLV_CONF,
+	vpn::{
+		backend::{SystemBackend, VpnBackend},
+		command::CommandRunner,
+		dns::Dns,
+		firewall::{AutoDetect, FirewallBackend},
+		util::{Config, KillSwitch},
+	},
+};
+use anyhow::Result;
+use console::Term;
+use directories::ProjectDirs;
+use std::io::Write;
+
+/// Stops the session recorded in [Config::connection_info] and clears it. Does not save the config to disk.
+///
+/// Dns settings are reverted before the tunnel goes down, so resolved still knows the link. A resolv.conf replaced for dns leak protection is only restored if no other program changed it in the meantime. Otherwise the original is moved aside, never deleted. A kill switch that is [KillSwitch::On] is removed even if there was no session, in case an earlier run left it behind.
+pub fn disconnect(config: &mut Config, pdir: &ProjectDirs, terminal: &mut Term) -> Result<()> {
+	disconnect_with(
+		config,
+		terminal,
+		&mut AutoDetect::default(),
+		&mut SystemBackend::new(pdir),
+		&mut Dns::default(),
+	)
+}
+
+/// [disconnect] with the firewall, vpn backend and dns handling passed in
+pub(crate) fn disconnect_with<R: CommandRunner>(
+	config: &mut Config,
+	terminal: &mut dyn Write,
+	firewall: &mut dyn FirewallBackend,
+	backend: &mut dyn VpnBackend,
+	dns: &mut Dns<R>,
+) -> Result<()> {
+	let kept_resolv_conf = dns.restore(&mut config.metadata)?;
+	if let Some(info) = &config.connection_info {
+		backend.down(info)?;
+		if info.ipv6_blocked {
+			firewall.unblock_ipv6()?;
+		}
+		writeln!(terminal, "Disconnected from {}", info.server_name)?;
+		config.connection_info = None;
+	} else {
+		writeln!(terminal, "Not connected to a ProtonVPN server")?;
+	}
+	if let Some(kept) = kept_resolv_conf {
+		writeln!(
+			terminal,
+			"Another program changed {} while connected, so it was left as is. The original was moved to {}",
+			RESOLV_CONF,
+			kept.display()
+		)?;
+	}
+	match config.user.killswitch {
+		KillSwitch::On => firewall.flush()?,
+		KillSwitch::AlwaysOn => writeln!(
+			terminal,
+			"The kill switch is always on, so only ProtonVPN servers are reachable"
+		)?,
+		KillSwitch::Off => {}
+	}
+	Ok(())
+}
@@ -8,15 +8,16 @@ use std::fs::create_dir;
 
 /// Asks for every setting and creates the app's config directories.
 pub fn initialize(config: &mut UserConfig, pdir: &ProjectDirs, terminal: &Term) -> Result<()> {
-	ask_for_settings(config, terminal)?;
-	create_config_dir(&pdir)?;
+	// The encrypted password file goes in there
+	create_config_dir(pdir)?;
+	ask_for_settings(config, pdir, terminal)?;
 	Ok(())
 }
 
-fn ask_for_settings(config: &mut UserConfig, terminal: &Term) -> Result<()> {
+fn ask_for_settings(config: &mut UserConfig, pdir: &ProjectDirs, terminal: &Term) -> Result<()> {
 	let mut user_settings = Settings::new(config.clone(), terminal);
 	user_settings.set_username()?;
-	user_settings.set_password()?;
+	user_settings.set_password(pdir)?;
 	user_settings.set_tier()?;
 	user_settings.set_protocol()?;
 	*config = user_settings.into_inner();
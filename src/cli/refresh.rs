@@ -0,0 +1,12 @@
+use crate::{utils, vpn::util::Config};
+use anyhow::Result;
+use console::Term;
+use directories::ProjectDirs;
+use std::io::Write;
+
+/// Re-downloads the server list and client config, ignoring the cache, and prints how the server list changed. Does not save the config to disk.
+pub fn refresh(config: &mut Config, pdir: &ProjectDirs, terminal: &mut Term) -> Result<()> {
+	let diff = utils::refresh(config, pdir)?;
+	writeln!(terminal, "Refreshed server list: {}", diff)?;
+	Ok(())
+}
@@ -0,0 +1,49 @@
+use super::SplitTunnelOptions::{self, *};
+use crate::{
+	constants::SPLIT_TUNNEL_FILE,
+	utils::config_path,
+	vpn::{split_tunnel::SplitTunnelList, util::UserConfig},
+};
+use anyhow::Result;
+use console::Term;
+use directories::ProjectDirs;
+use std::io::Write;
+
+/// Edits or prints the [split tunnel file](crate::constants::SPLIT_TUNNEL_FILE). Nothing is saved unless every entry is valid.
+pub fn split_tunnel(
+	option: &SplitTunnelOptions,
+	user: &UserConfig,
+	pdir: &ProjectDirs,
+	terminal: &mut Term,
+) -> Result<()> {
+	let path = config_path(pdir, SPLIT_TUNNEL_FILE);
+	let mut list = SplitTunnelList::load(&path)?;
+	match option {
+		Add { networks } => {
+			for network in networks {
+				list.add(network.clone())?;
+			}
+		}
+		Remove { networks } => {
+			for network in networks {
+				list.remove(network)?;
+			}
+		}
+		Clear => list.clear(),
+		List => {
+			for entry in list.entries() {
+				writeln!(terminal, "{}", entry)?;
+			}
+		}
+	}
+	if !matches!(option, List) {
+		list.save(&path)?;
+	}
+	if !user.split_tunnel {
+		writeln!(
+			terminal,
+			"Split tunneling is off, so these networks still go through the vpn. Turn it on with `protonvpn configure`."
+		)?;
+	}
+	Ok(())
+}
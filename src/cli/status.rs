@@ -0,0 +1,230 @@
+use crate::{
+	utils::{country_name, ip_info, IpInfo},
+	vpn::{
+		backend::{SystemBackend, TunnelStatus, VpnBackend},
+		management::State,
+		util::{Config, ConnectionInfo},
+	},
+};
+use anyhow::{anyhow, Result};
+use chrono::{DateTime, Local, Utc};
+use console::Term;
+use directories::ProjectDirs;
+use std::io::Write;
+
+/// Prints information about the session in [Config::connection_info]. Fails if no session is up, so the exit status can be checked in scripts.
+pub fn status(config: &Config, pdir: &ProjectDirs, terminal: &mut Term) -> Result<()> {
+	status_with(config, terminal, &mut SystemBackend::new(pdir), || {
+		ip_info(config).ok()
+	})
+}
+
+/// [status] with the vpn backend and the public ip lookup passed in
+pub(crate) fn status_with<F>(
+	config: &Config,
+	terminal: &mut dyn Write,
+	backend: &mut dyn VpnBackend,
+	ip: F,
+) -> Result<()>
+where
+	F: FnOnce() -> Option<IpInfo>,
+{
+	let (info, TunnelStatus { state, traffic }) = match &config.connection_info {
+		Some(info) => match backend.status(info) {
+			Some(status) => (info, status),
+			None => {
+				return Err(anyhow!(
+					"The connection to {} ended unexpectedly. Run `protonvpn disconnect` to clean up",
+					info.server_name
+				))
+			}
+		},
+		None => return Err(anyhow!("Not connected to a ProtonVPN server")),
+	};
+
+	let ip = ip();
+	write!(
+		terminal,
+		"{}",
+		render_status(info, state.as_ref(), ip.as_ref(), traffic, Utc::now())
+	)?;
+	Ok(())
+}
+
+/// The status report. Values that couldn't be found are shown as unknown.
+fn render_status(
+	info: &ConnectionInfo,
+	state: Option<&State>,
+	ip: Option<&IpInfo>,
+	traffic: Option<(u64, u64)>,
+	now: DateTime<Utc>,
+) -> String {
+	let unknown = || "unknown".to_string();
+	let country = if info.entry_country == info.exit_country {
+		country_name(&info.exit_country).to_string()
+	} else {
+		format!(
+			"{} via {}",
+			country_name(&info.exit_country),
+			country_name(&info.entry_country)
+		)
+	};
+	let since = format!(
+		"{} ({} ago)",
+		info.connected_time
+			.with_timezone(&Local)
+			.format("%Y-%m-%d %H:%M:%S"),
+		format_duration(now - info.connected_time)
+	);
+	let ip = ip.map_or_else(unknown, |ip| format!("{} ({})", ip.ip, ip.isp));
+	let (received, sent) = match traffic {
+		Some((rx, tx)) => (format_bytes(rx), format_bytes(tx)),
+		None => (unknown(), unknown()),
+	};
+
+	let status = state.map_or_else(
+		|| "Connected".to_string(),
+		|state| match state.description.as_str() {
+			"" | "SUCCESS" => state.name.clone(),
+			description => format!("{} ({})", state.name, description),
+		},
+	);
+	let tunnel_ip = state
+		.and_then(|state| state.local_ip)
+		.map_or_else(unknown, |ip| ip.to_string());
+
+	let dns = info
+		.dns_server
+		.map_or_else(|| "system default".to_string(), |dns| dns.to_string());
+
+	let mut rows = vec![
+		("Status", status),
+		("Server", info.server_name.clone()),
+		("Country", country),
+		("Protocol", info.protocol.to_string()),
+		("Interface", info.interface.clone()),
+		("Tunnel IP", tunnel_ip),
+		("DNS", dns),
+		("Connected", since),
+		("IP", ip),
+		("Received", received),
+		("Sent", sent),
+	];
+	if !info.split_domains.is_empty() {
+		let domains: Vec<_> = info.split_domains.iter().map(|d| d.to_string()).collect();
+		rows.push(("Split", domains.join(", ")));
+	}
+	rows.iter()
+		.map(|(name, value)| format!("{:<11}{}\n", format!("{}:", name), value))
+		.collect()
+}
+
+/// Formats like `1:02:03`
+fn format_duration(duration: chrono::Duration) -> String {
+	let seconds = duration.num_seconds().max(0);
+	format!(
+		"{}:{:02}:{:02}",
+		seconds / 3600,
+		seconds / 60 % 60,
+		seconds % 60
+	)
+}
+
+/// Formats a byte count with binary prefixes, like `1.5 MiB`
+fn format_bytes(bytes: u64) -> String {
+	const UNITS: [&str; 5] = ["B", "KiB", "MiB", "GiB", "TiB"];
+	let mut value = bytes as f64;
+	let mut unit = 0;
+	while value >= 1024.0 && unit < UNITS.len() - 1 {
+		value /= 1024.0;
+		unit += 1;
+	}
+	if unit == 0 {
+		format!("{} B", bytes)
+	} else {
+		format!("{:.1} {}", value, UNITS[unit])
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::{
+		utils::LogicalServer,
+		vpn::{backend::Handle, split_tunnel::ResolvedDomain, util::ConnectionProtocol},
+	};
+	use chrono::Duration;
+
+	#[test]
+	fn test_format_bytes() {
+		assert_eq!(format_bytes(0), "0 B");
+		assert_eq!(format_bytes(1023), "1023 B");
+		assert_eq!(format_bytes(1536), "1.5 KiB");
+		assert_eq!(format_bytes(5 * 1024 * 1024 * 1024), "5.0 GiB");
+	}
+
+	#[test]
+	fn test_render_status() {
+		let now = Utc::now();
+		let mut server = LogicalServer::mock("IS-DE#1", 2, 1.0, 10);
+		server.entry_country = "IS".into();
+		server.exit_country = "DE".into();
+		let info = ConnectionInfo {
+			dns_server: Some("10.8.8.1".parse().unwrap()),
+			split_domains: vec![ResolvedDomain {
+				domain: "git.example.com".into(),
+				addresses: vec!["192.0.2.1".parse().unwrap()],
+			}],
+			connected_time: now - Duration::seconds(3723),
+			..ConnectionInfo::new(
+				&server,
+				ConnectionProtocol::UDP,
+				"proton0",
+				Handle::OpenVpn {
+					pid: 1,
+					management_socket: "/tmp/management.sock".into(),
+				},
+			)
+		};
+		let ip = IpInfo {
+			ip: "185.159.157.1".parse().unwrap(),
+			isp: "Proton AG".into(),
+		};
+
+		let state = State {
+			time: 1611234567,
+			name: "CONNECTED".into(),
+			description: "SUCCESS".into(),
+			local_ip: Some("10.8.0.2".parse().unwrap()),
+			remote_ip: None,
+		};
+
+		let status = render_status(&info, Some(&state), Some(&ip), Some((2048, 10)), now);
+		assert!(status.contains("Status:    CONNECTED\n"), "{}", status);
+		assert!(status.contains("Tunnel IP: 10.8.0.2\n"), "{}", status);
+		assert!(status.contains("DNS:       10.8.8.1\n"), "{}", status);
+		assert!(status.contains("Server:    IS-DE#1\n"), "{}", status);
+		assert!(
+			status.contains("Country:   Germany via Iceland\n"),
+			"{}",
+			status
+		);
+		assert!(status.contains("(1:02:03 ago)"), "{}", status);
+		assert!(
+			status.contains("IP:        185.159.157.1 (Proton AG)\n"),
+			"{}",
+			status
+		);
+		assert!(status.contains("Received:  2.0 KiB\n"), "{}", status);
+		assert!(status.contains("Sent:      10 B\n"), "{}", status);
+		assert!(
+			status.contains("Split:     git.example.com (192.0.2.1)\n"),
+			"{}",
+			status
+		);
+
+		let status = render_status(&info, None, None, None, now);
+		assert!(status.contains("Status:    Connected\n"), "{}", status);
+		assert!(status.contains("IP:        unknown\n"), "{}", status);
+	}
+}
@@ -268,8 +268,35 @@ pub const VERSION: &str = structopt::clap::crate_version!();
 /// Filename for split tunnel ip masks. This file should be read/written by the app.  
 pub const SPLIT_TUNNEL_FILE: &str = "split_tunnel.txt";
 
+/// Cached response of the `/vpn/logicals` api endpoint.
+pub const SERVER_INFO_FILE: &str = "servers.json";
+
+/// Cached response of the `/vpn/clientconfig` api endpoint.
+pub const CLIENT_CONFIG_FILE: &str = "clientconfig.json";
+
+/// The system's dns config, replaced while connected if dns leak protection is on.
+pub const RESOLV_CONF: &str = "/etc/resolv.conf";
+
 /// Name of the openvpn config file. Eventually we want to replace this with tempfiles.
 pub const OVPN_FILE: &str = "connect.ovpn";
 
+/// Name of the tun device openvpn creates.
+pub const TUN_DEVICE: &str = "proton0";
+
+/// Unix socket for openvpn's management interface.
+pub const MANAGEMENT_SOCKET: &str = "management.sock";
+
 /// Openvpn logs. Used for debugging
 pub const OVPN_LOG: &str = "ovpn.log";
+
+/// Name of the wireguard interface. wg-quick names the interface after its config file, so this must match [WG_FILE].
+pub const WG_DEVICE: &str = "protonwg0";
+
+/// Name of the wg-quick config file, which holds the private key while connected.
+pub const WG_FILE: &str = "protonwg0.conf";
+
+/// The wireguard private key, generated on first use.
+pub const WG_KEY_FILE: &str = "wireguard.key";
+
+/// The password, encrypted with a passphrase, for when there is no keyring.
+pub const PASSWORD_FILE: &str = "password.enc";
@@ -8,12 +8,14 @@
 #![deny(broken_intra_doc_links)]
 
 use crate::{
-	cli::{configure, connect, initialize, CliOptions},
-	constants::APP_NAME,
-	utils::project_dirs,
+	cli::{
+		configure, connect, disconnect, initialize, reconnect, refresh, split_tunnel, status,
+		CliOptions,
+	},
+	utils::{project_dirs, store_config},
 	vpn::util::Config,
 };
-use anyhow::{Context, Result};
+use anyhow::Result;
 use confy::ConfyError;
 use dialoguer::console::Term;
 use std::io::Write;
@@ -26,6 +28,8 @@ pub mod cli;
 
 /// Assorted constants for use throughout the crate. Mostly strings and a map for looking up countries.
 pub mod constants;
+/// Keeping the ProtonVPN password out of the config file.
+pub(crate) mod secrets;
 /// Miscellaneous functions. See the documentation for this module's members instead
 pub(crate) mod utils;
 /// Functions for interacting with the `openvpn` binary, including starting / stopping a connection, and creating config files.
@@ -43,27 +47,51 @@ pub fn main(
 	let pdir = project_dirs();
 
 	if let Ok(mut config) = config_res {
+		if secrets::migrate(&mut config.user, &pdir)? {
+			store_config(&config)?;
+			writeln!(
+				terminal,
+				"Moved your password out of the config file, into the {}",
+				config.user.password_store
+			)?;
+		}
 		match opt {
 			Init => {
 				initialize(&mut config.user, &pdir, terminal)?;
-				confy::store(APP_NAME, config.user).context("Couldn't store your configuration")?;
+				store_config(&config)?;
 			}
 			Connect(flags) => {
-				let _connection = connect(&flags, &mut config, &pdir)?;
+				let res = connect(&flags, &mut config, &pdir, terminal);
+				store_config(&config)?;
+				res?;
 			}
-			Reconnect => {}
-			Disconnect => {}
-			Status => {}
+			Reconnect => {
+				let res = reconnect(&mut config, &pdir, terminal);
+				store_config(&config)?;
+				res?;
+			}
+			Disconnect => {
+				disconnect(&mut config, &pdir, terminal)?;
+				store_config(&config)?;
+			}
+			Status => status(&config, &pdir, terminal)?,
 			Configure => {
-				configure(&mut config.user, terminal)?;
-				confy::store(APP_NAME, config.user).context("Couldn't store your configuration")?;
+				configure(&mut config.user, &pdir, terminal)?;
+				store_config(&config)?;
+			}
+			Refresh => {
+				let res = refresh(&mut config, &pdir, terminal);
+				store_config(&config)?;
+				res?;
 			}
-			Refresh => {}
+			SplitTunnel(option) => split_tunnel(&option, &config.user, &pdir, terminal)?,
 			Examples => {}
 		};
 	} else {
 		if let Init = opt {
-			initialize(&mut Default::default(), &pdir, terminal)?;
+			let mut config = Config::default();
+			initialize(&mut config.user, &pdir, terminal)?;
+			store_config(&config)?;
 		} else {
 			writeln!(
 				terminal,
@@ -10,5 +10,7 @@ fn main(args: CliOptions) -> Result<()> {
 
 	let config = load::<Config>(APP_NAME);
 
-	main_cli(args, config, &mut terminal)
+	let res = main_cli(args, config, &mut terminal);
+	terminal.flush()?;
+	res
 }
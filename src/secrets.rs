@@ -0,0 +1,499 @@
+use std::{
+	env,
+	fs::read,
+	io::Read,
+	path::{Path, PathBuf},
+	process::{Child, Command, ExitStatus, Stdio},
+	thread::{sleep, spawn},
+	time::{Duration, Instant},
+};
+
+use anyhow::{anyhow, bail, Context, Result};
+use chacha20poly1305::{
+	aead::{Aead, KeyInit},
+	Key, XChaCha20Poly1305, XNonce,
+};
+use dialoguer::{console::Term, theme::ColorfulTheme, Password};
+use directories::ProjectDirs;
+use rand::{rngs::OsRng, RngCore};
+use scrypt::{scrypt, Params};
+
+use crate::{
+	constants::{APP_NAME, PASSWORD_FILE},
+	utils::{config_path, write_atomic},
+	vpn::{
+		command::{CommandRunner, SystemRunner},
+		remove_if_exists,
+		util::{PasswordStore, UserConfig},
+	},
+};
+
+/// Holds the password of a ProtonVPN account
+pub(crate) trait SecretStore {
+	/// The password stored for `username`
+	fn load(&mut self, username: &str) -> Result<String>;
+	/// Stores `password` for `username`, replacing the old one
+	fn save(&mut self, username: &str, password: &str) -> Result<()>;
+}
+
+/// The desktop's keyring, through libsecret's `secret-tool`. Needs a running Secret Service, like gnome-keyring or kwallet, and the user's D-Bus session to reach it.
+pub(crate) struct Keyring<R> {
+	runner: R,
+	/// Whether a D-Bus session bus was found, see [has_session_bus]
+	session_bus: bool,
+}
+
+impl<R> Keyring<R> {
+	/// `secret-tool` only reports that it found nothing when it can't reach the keyring, so a missing session bus is caught here
+	fn check_session_bus(&self) -> Result<()> {
+		if !self.session_bus {
+			bail!("The keyring can't be reached without your desktop session's D-Bus. It doesn't work under sudo or over ssh, use the encrypted password file or password_command there");
+		}
+		Ok(())
+	}
+}
+
+/// Whether this process can reach a D-Bus session bus. Sudo and ssh sessions usually can't.
+fn has_session_bus() -> bool {
+	env::var_os("DBUS_SESSION_BUS_ADDRESS").is_some()
+		|| env::var_os("XDG_RUNTIME_DIR").is_some_and(|dir| Path::new(&dir).join("bus").exists())
+}
+
+impl<R: CommandRunner> SecretStore for Keyring<R> {
+	fn load(&mut self, username: &str) -> Result<String> {
+		self.check_session_bus()?;
+		let password = self
+			.runner
+			.run(
+				"secret-tool",
+				&["lookup", "service", APP_NAME, "username", username],
+			)
+			.with_context(|| format!("The keyring has no password for {}", username))?;
+		Ok(password.trim_end_matches('\n').into())
+	}
+
+	fn save(&mut self, username: &str, password: &str) -> Result<()> {
+		self.check_session_bus()?;
+		self.runner
+			.run_with_input(
+				"secret-tool",
+				&[
+					"store",
+					"--label",
+					"ProtonVPN",
+					"service",
+					APP_NAME,
+					"username",
+					username,
+				],
+				password,
+			)
+			.context("Couldn't store the password in the keyring")?;
+		Ok(())
+	}
+}
+
+/// Keeps passwords in memory. For tests.
+#[cfg(test)]
+#[derive(Debug, Default)]
+pub(crate) struct Memory {
+	pub(crate) passwords: std::collections::HashMap<String, String>,
+}
+
+#[cfg(test)]
+impl SecretStore for Memory {
+	fn load(&mut self, username: &str) -> Result<String> {
+		self.passwords
+			.get(username)
+			.cloned()
+			.ok_or_else(|| anyhow!("No password for {}", username))
+	}
+
+	fn save(&mut self, username: &str, password: &str) -> Result<()> {
+		self.passwords.insert(username.into(), password.into());
+		Ok(())
+	}
+}
+
+/// Format of [EncryptedFile]
+const FILE_VERSION: u8 = 1;
+const SALT_LEN: usize = 16;
+const NONCE_LEN: usize = 24;
+
+/// A file encrypted with XChaCha20-Poly1305, under a key derived from a passphrase with scrypt. It holds the format version, the scrypt cost, the salt, the nonce and the ciphertext, in that order.
+pub(crate) struct EncryptedFile<P> {
+	path: PathBuf,
+	/// Asks for the passphrase. Its argument is true when a new passphrase is chosen, which should be confirmed.
+	passphrase: P,
+	/// Scrypt's cost for new files, as the log2 of its N parameter
+	log_n: u8,
+}
+
+impl<P> EncryptedFile<P>
+where
+	P: FnMut(bool) -> Result<String>,
+{
+	/// Keeps the password at `path`
+	pub(crate) fn new(path: PathBuf, passphrase: P) -> Self {
+		Self {
+			path,
+			passphrase,
+			log_n: Params::RECOMMENDED_LOG_N,
+		}
+	}
+}
+
+fn derive_key(passphrase: &str, salt: &[u8], log_n: u8) -> Result<Key> {
+	let params = Params::new(log_n, Params::RECOMMENDED_R, Params::RECOMMENDED_P, 32)
+		.map_err(|e| anyhow!("Invalid scrypt cost {}: {}", log_n, e))?;
+	let mut key = Key::default();
+	scrypt(passphrase.as_bytes(), salt, &params, &mut key)
+		.map_err(|e| anyhow!("Couldn't derive a key from the passphrase: {}", e))?;
+	Ok(key)
+}
+
+impl<P> SecretStore for EncryptedFile<P>
+where
+	P: FnMut(bool) -> Result<String>,
+{
+	fn load(&mut self, _username: &str) -> Result<String> {
+		let Self {
+			path, passphrase, ..
+		} = self;
+		let contents = read(&path).with_context(|| {
+			format!(
+				"Couldn't read {}. Set your password with `protonvpn configure`",
+				path.display()
+			)
+		})?;
+		let damaged = || anyhow!("{} is damaged", path.display());
+		let (log_n, rest) = match contents.as_slice() {
+			[FILE_VERSION, log_n, rest @ ..] if rest.len() > SALT_LEN + NONCE_LEN => (*log_n, rest),
+			_ => return Err(damaged()),
+		};
+		let (salt, rest) = rest.split_at(SALT_LEN);
+		let (nonce, ciphertext) = rest.split_at(NONCE_LEN);
+
+		let key = derive_key(&passphrase(false)?, salt, log_n)?;
+		let password = XChaCha20Poly1305::new(&key)
+			.decrypt(XNonce::from_slice(nonce), ciphertext)
+			.map_err(|_| anyhow!("Wrong passphrase for {}", path.display()))?;
+		String::from_utf8(password).map_err(|_| damaged())
+	}
+
+	fn save(&mut self, _username: &str, password: &str) -> Result<()> {
+		let mut salt = [0; SALT_LEN];
+		let mut nonce = [0; NONCE_LEN];
+		OsRng.fill_bytes(&mut salt);
+		OsRng.fill_bytes(&mut nonce);
+
+		let key = derive_key(&(self.passphrase)(true)?, &salt, self.log_n)?;
+		let ciphertext = XChaCha20Poly1305::new(&key)
+			.encrypt(XNonce::from_slice(&nonce), password.as_bytes())
+			.map_err(|_| anyhow!("Couldn't encrypt the password"))?;
+
+		let mut contents = vec![FILE_VERSION, self.log_n];
+		contents.extend_from_slice(&salt);
+		contents.extend_from_slice(&nonce);
+		contents.extend_from_slice(&ciphertext);
+		write_atomic(&self.path, &contents)
+	}
+}
+
+/// How long [PasswordCommand] waits. Long enough to type a gpg passphrase.
+const PASSWORD_COMMAND_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// The user's [password_command](UserConfig::password_command), run with `sh -c`. The first line it prints is the password, like `pass show` prints it. Stdin and stderr are the terminal's, so it can ask for a passphrase.
+///
+/// What it prints is never shown. Errors only mention its exit status.
+pub(crate) struct PasswordCommand {
+	command: String,
+	timeout: Duration,
+}
+
+impl PasswordCommand {
+	/// Runs `command` with the default timeout
+	pub(crate) fn new(command: String) -> Self {
+		Self {
+			command,
+			timeout: PASSWORD_COMMAND_TIMEOUT,
+		}
+	}
+}
+
+impl SecretStore for PasswordCommand {
+	fn load(&mut self, _username: &str) -> Result<String> {
+		let mut child = Command::new("sh")
+			.arg("-c")
+			.arg(&self.command)
+			.stdout(Stdio::piped())
+			.spawn()
+			.context("Couldn't run password_command")?;
+		let mut stdout = child.stdout.take().context("stdout wasn't piped")?;
+		// Reading in the background keeps a command with lots of output from blocking on a full pipe
+		let reader = spawn(move || {
+			let mut output = String::new();
+			stdout.read_to_string(&mut output).map(|_| output)
+		});
+
+		let status = match wait_timeout(&mut child, self.timeout)? {
+			Some(status) => status,
+			None => {
+				child.kill()?;
+				child.wait()?;
+				bail!("password_command didn't finish within {:?}", self.timeout);
+			}
+		};
+		if !status.success() {
+			bail!("password_command failed ({})", status);
+		}
+		let output = reader
+			.join()
+			.map_err(|_| anyhow!("Couldn't read the output of password_command"))?
+			.context("password_command didn't print valid utf-8")?;
+		match output.lines().next() {
+			Some(password) if !password.is_empty() => Ok(password.into()),
+			_ => bail!("password_command didn't print a password"),
+		}
+	}
+
+	fn save(&mut self, _username: &str, _password: &str) -> Result<()> {
+		bail!("The password comes from password_command, so it can't be changed here. Clear password_command to store one")
+	}
+}
+
+/// Waits for `child` to exit. Returns `None` if it is still running after `timeout`.
+fn wait_timeout(child: &mut Child, timeout: Duration) -> Result<Option<ExitStatus>> {
+	let start = Instant::now();
+	loop {
+		if let Some(status) = child.try_wait()? {
+			return Ok(Some(status));
+		}
+		if start.elapsed() >= timeout {
+			return Ok(None);
+		}
+		sleep(Duration::from_millis(50));
+	}
+}
+
+/// Asks for the passphrase of the [EncryptedFile] on the terminal
+fn ask_passphrase(new: bool) -> Result<String> {
+	let theme = ColorfulTheme::default();
+	let mut prompt = Password::with_theme(&theme);
+	prompt.with_prompt("Passphrase for the password file");
+	if new {
+		prompt.with_confirmation("Confirm passphrase", "Passphrases mismatching");
+	}
+	Ok(prompt.interact_on(&Term::stderr())?)
+}
+
+/// The store `kind`, backed by the system's keyring or a file in the app's config dir
+pub(crate) fn open(kind: PasswordStore, pdir: &ProjectDirs) -> Box<dyn SecretStore> {
+	match kind {
+		PasswordStore::Keyring => Box::new(Keyring {
+			runner: SystemRunner,
+			session_bus: has_session_bus(),
+		}),
+		PasswordStore::EncryptedFile => Box::new(EncryptedFile::new(
+			config_path(pdir, PASSWORD_FILE),
+			ask_passphrase,
+		)),
+	}
+}
+
+/// Where `user`'s password comes from: their [password_command](UserConfig::password_command) if they have one, otherwise their [PasswordStore]
+pub(crate) fn for_user(user: &UserConfig, pdir: &ProjectDirs) -> Box<dyn SecretStore> {
+	match &user.password_command {
+		Some(command) => Box::new(PasswordCommand::new(command.clone())),
+		None => open(user.password_store, pdir),
+	}
+}
+
+/// Stores the password in the keyring if there is one, otherwise in the encrypted file. Returns the store it went to.
+pub(crate) fn save_password(
+	username: &str,
+	password: &str,
+	pdir: &ProjectDirs,
+) -> Result<PasswordStore> {
+	let store = save_first(
+		&mut *open(PasswordStore::Keyring, pdir),
+		&mut *open(PasswordStore::EncryptedFile, pdir),
+		username,
+		password,
+	)?;
+	if store == PasswordStore::Keyring {
+		// An older password shouldn't linger
+		remove_if_exists(&config_path(pdir, PASSWORD_FILE))?;
+	}
+	Ok(store)
+}
+
+fn save_first(
+	keyring: &mut dyn SecretStore,
+	file: &mut dyn SecretStore,
+	username: &str,
+	password: &str,
+) -> Result<PasswordStore> {
+	match keyring.save(username, password) {
+		Ok(()) => Ok(PasswordStore::Keyring),
+		Err(e) => {
+			eprintln!(
+				"{:#}. The password will be encrypted with a passphrase instead",
+				e
+			);
+			file.save(username, password)?;
+			Ok(PasswordStore::EncryptedFile)
+		}
+	}
+}
+
+/// Moves the plaintext password of an old config into a [SecretStore]. Returns whether there was one, in which case the config should be saved again.
+pub(crate) fn migrate(user: &mut UserConfig, pdir: &ProjectDirs) -> Result<bool> {
+	if user.legacy_password.is_empty() {
+		return Ok(false);
+	}
+	user.password_store = save_password(&user.username, &user.legacy_password, pdir)?;
+	user.legacy_password.clear();
+	Ok(true)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::vpn::command::FakeRunner;
+	use tempfile::tempdir;
+
+	#[test]
+	fn test_keyring() -> Result<()> {
+		let mut keyring = Keyring {
+			runner: FakeRunner::default(),
+			session_bus: true,
+		};
+		keyring.save("user", "hunter2")?;
+		assert_eq!(
+			keyring.runner.commands,
+			["secret-tool store --label ProtonVPN service protonvpn-rs username user"]
+		);
+		assert_eq!(keyring.runner.inputs, ["hunter2"]);
+
+		keyring.runner.stdout = "hunter2\n".into();
+		assert_eq!(keyring.load("user")?, "hunter2");
+		assert_eq!(
+			keyring.runner.commands[1],
+			"secret-tool lookup service protonvpn-rs username user"
+		);
+
+		keyring.runner.failing.push("secret-tool");
+		let err = keyring.load("user").unwrap_err();
+		assert_eq!(err.to_string(), "The keyring has no password for user");
+
+		// Without a session bus, secret-tool isn't even tried
+		keyring.session_bus = false;
+		keyring.runner.commands.clear();
+		let err = keyring.load("user").unwrap_err();
+		assert!(
+			err.to_string().contains("doesn't work under sudo"),
+			"{}",
+			err
+		);
+		assert!(keyring.save("user", "hunter2").is_err());
+		assert!(keyring.runner.commands.is_empty());
+		Ok(())
+	}
+
+	#[test]
+	fn test_encrypted_file() -> Result<()> {
+		use std::os::unix::fs::PermissionsExt;
+
+		let dir = tempdir()?;
+		let path = dir.path().join("password.enc");
+		let mut asked = vec![];
+		let mut file = EncryptedFile {
+			path: path.clone(),
+			passphrase: |new| {
+				asked.push(new);
+				Ok("correct horse".to_string())
+			},
+			// Cheap enough for tests
+			log_n: 4,
+		};
+		assert!(file.load("user").is_err());
+
+		file.save("user", "hunter2")?;
+		assert_eq!(file.load("user")?, "hunter2");
+		assert_eq!(path.metadata()?.permissions().mode() & 0o777, 0o600);
+		let contents = read(&path)?;
+		assert_eq!(contents[..2], [FILE_VERSION, 4]);
+		assert!(!contents.windows(7).any(|w| w == b"hunter2"));
+
+		let mut wrong = EncryptedFile::new(path.clone(), |_| Ok("wrong".to_string()));
+		let err = wrong.load("user").unwrap_err();
+		assert_eq!(
+			err.to_string(),
+			format!("Wrong passphrase for {}", path.display())
+		);
+
+		write_atomic(&path, &contents[..20])?;
+		let err = file.load("user").unwrap_err();
+		assert_eq!(err.to_string(), format!("{} is damaged", path.display()));
+		drop(file);
+		assert_eq!(asked, [true, false]);
+		Ok(())
+	}
+
+	#[test]
+	fn test_password_command() -> Result<()> {
+		let run = |command: &str| {
+			PasswordCommand {
+				command: command.into(),
+				timeout: Duration::from_millis(500),
+			}
+			.load("user")
+		};
+		assert_eq!(run("printf 'hunter2\\nlogin: user\\n'")?, "hunter2");
+		assert_eq!(run("printf hunter2")?, "hunter2");
+
+		let err = run("echo hunter2; exit 3").unwrap_err().to_string();
+		assert_eq!(err, "password_command failed (exit status: 3)");
+		assert!(!err.contains("hunter2"));
+
+		let err = run("true").unwrap_err();
+		assert_eq!(err.to_string(), "password_command didn't print a password");
+
+		let start = Instant::now();
+		let err = run("sleep 10").unwrap_err();
+		assert_eq!(
+			err.to_string(),
+			"password_command didn't finish within 500ms"
+		);
+		assert!(start.elapsed() < Duration::from_secs(5));
+
+		assert!(PasswordCommand::new("true".into())
+			.save("user", "hunter2")
+			.is_err());
+		Ok(())
+	}
+
+	#[test]
+	fn test_save_first() -> Result<()> {
+		let dir = tempdir()?;
+		let mut keyring = Keyring {
+			runner: FakeRunner::default(),
+			session_bus: true,
+		};
+		let mut file = EncryptedFile {
+			path: dir.path().join("password.enc"),
+			passphrase: |_| Ok("correct horse".to_string()),
+			log_n: 4,
+		};
+		let store = save_first(&mut keyring, &mut file, "user", "hunter2")?;
+		assert_eq!(store, PasswordStore::Keyring);
+		assert!(!file.path.exists());
+
+		keyring.runner.failing.push("secret-tool");
+		let store = save_first(&mut keyring, &mut file, "user", "hunter2")?;
+		assert_eq!(store, PasswordStore::EncryptedFile);
+		assert_eq!(file.load("user")?, "hunter2");
+		Ok(())
+	}
+}
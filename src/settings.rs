@@ -1,10 +1,19 @@
 //! The functions in this module are assumed to work, being short, resuable, wrappers around external library. They have been tested by hand, but currently can't be tested programmatically because console doesn't have a testing functionality.
 
-use crate::vpn::util::{ConnectionProtocol, PlanTier, UserConfig};
+use crate::{
+	constants::WG_KEY_FILE,
+	secrets,
+	utils::config_path,
+	vpn::{
+		util::{Backend, ConnectionProtocol, KillSwitch, PasswordStore, PlanTier, UserConfig},
+		wireguard::KeyPair,
+	},
+};
 use anyhow::Result;
 use dialoguer::{console::Term, theme::ColorfulTheme};
+use directories::ProjectDirs;
 
-use std::{fmt::Display, mem::replace, str::FromStr};
+use std::{fmt::Display, io::Write, mem::replace, str::FromStr};
 use strum::IntoEnumIterator;
 
 /// Encapsulation for mutating ProtonVPN Settings.
@@ -28,9 +37,21 @@ impl<'a, S> Settings<'a, S> {
 		T: Display + Copy + IntoEnumIterator,
 		N: AsRef<str>,
 	{
-		use dialoguer::Select;
+		self.choose_field(name, T::iter().collect(), getter)
+	}
 
-		let options: Vec<T> = T::iter().collect();
+	/// Like [set_enum_field](Self::set_enum_field), but only offers `options`
+	fn choose_field<T, N>(
+		&mut self,
+		name: N,
+		options: Vec<T>,
+		getter: impl Fn(&mut S) -> &mut T,
+	) -> Result<T>
+	where
+		T: Display + Copy,
+		N: AsRef<str>,
+	{
+		use dialoguer::Select;
 
 		let new_value = Select::with_theme(&ColorfulTheme::default())
 			.with_prompt(name.as_ref())
@@ -75,51 +96,84 @@ impl<'a> Settings<'a, UserConfig> {
 		self.set_enum_field("Plan Tier", |t| &mut t.tier)
 	}
 
+	pub(crate) fn set_server_cache_minutes(&mut self) -> Result<u8> {
+		self.set_value_field("Server list refresh interval (minutes)", |u| {
+			&mut u.server_cache_minutes
+		})
+	}
+
+	pub(crate) fn set_split_tunnel(&mut self) -> Result<bool> {
+		self.set_value_field("Split tunneling (true or false)", |u| &mut u.split_tunnel)
+	}
+
+	pub(crate) fn set_killswitch(&mut self) -> Result<KillSwitch> {
+		self.set_enum_field("Kill Switch", |u| &mut u.killswitch)
+	}
+
+	pub(crate) fn set_allow_lan(&mut self) -> Result<bool> {
+		self.set_value_field(
+			"Local network access with the kill switch (true or false)",
+			|u| &mut u.allow_lan,
+		)
+	}
+
+	/// Wireguard is only offered once its key is confirmed, see [confirm_wireguard_key](Self::confirm_wireguard_key)
+	pub(crate) fn set_backend(&mut self) -> Result<Backend> {
+		if self.settings.wireguard_public_key.is_none() {
+			writeln!(
+				self.terminal,
+				"Wireguard needs a registered key. Confirm it under \"Wireguard key\" first"
+			)?;
+			return self.choose_field("Vpn Backend", vec![Backend::OpenVpn], |u| &mut u.backend);
+		}
+		self.set_enum_field("Vpn Backend", |u| &mut u.backend)
+	}
+
+	/// Shows the wireguard public key, generating the keypair if there is none, and asks whether it is registered with the account. Servers reject unregistered keys.
+	pub(crate) fn confirm_wireguard_key(&mut self, pdir: &ProjectDirs) -> Result<Option<String>> {
+		use dialoguer::Confirm;
+
+		let public_key = KeyPair::load_or_generate(&config_path(pdir, WG_KEY_FILE))?.public_key();
+		writeln!(self.terminal, "Your wireguard public key is {}", public_key)?;
+		let registered = Confirm::with_theme(&ColorfulTheme::default())
+			.with_prompt("Is this key registered with your ProtonVPN account?")
+			.default(false)
+			.interact_on(self.terminal)?;
+		let confirmed = registered.then_some(public_key);
+		if confirmed.is_none() && self.settings.backend == Backend::WireGuard {
+			self.settings.backend = Backend::OpenVpn;
+			writeln!(self.terminal, "Switched back to openvpn")?;
+		}
+		Ok(replace(&mut self.settings.wireguard_public_key, confirmed))
+	}
+
+	/// A shell command that prints the password, for password managers. Leaving it empty goes back to the stored password.
+	pub(crate) fn set_password_command(&mut self) -> Result<Option<String>> {
+		use dialoguer::Input;
+
+		let command = Input::<String>::with_theme(&ColorfulTheme::default())
+			.with_prompt("Password command (empty to use the stored password)")
+			.allow_empty(true)
+			.interact_on(self.terminal)?;
+		let command = Some(command.trim().to_string()).filter(|c| !c.is_empty());
+		Ok(replace(&mut self.settings.password_command, command))
+	}
+
 	pub(crate) fn set_protocol(&mut self) -> Result<ConnectionProtocol> {
 		self.set_enum_field("Connection Protocol", |u| &mut u.protocol)
 	}
 
-	pub(crate) fn set_password(&mut self) -> Result<String> {
+	/// Asks for the password and saves it in a [secret store](crate::secrets), never in the config. Set the username first, the keyring files the password under it.
+	pub(crate) fn set_password(&mut self, pdir: &ProjectDirs) -> Result<PasswordStore> {
 		use dialoguer::Password;
 
 		let pass = Password::with_theme(&ColorfulTheme::default())
 			.with_prompt("Password")
 			.with_confirmation("Confirm password", "Passwords mismatching")
 			.interact_on(self.terminal)?;
-		let old = replace(&mut self.settings.password, pass);
+		let store = secrets::save_password(&self.settings.username, &pass, pdir)?;
+		writeln!(self.terminal, "Your password is in the {}", store)?;
+		let old = replace(&mut self.settings.password_store, store);
 		Ok(old)
 	}
 }
-
-fn get_enum_field<T, N>(terminal: &Term, name: N) -> Result<T>
-where
-	T: Display + Copy + IntoEnumIterator,
-	N: AsRef<str>,
-{
-	use dialoguer::Select;
-
-	let options: Vec<T> = T::iter().collect();
-
-	let new_value = Select::with_theme(&ColorfulTheme::default())
-		.with_prompt(name.as_ref())
-		.default(0)
-		.items(&options)
-		.interact_on(terminal)?;
-
-	Ok(options[new_value])
-}
-
-fn ask_for<T, N>(terminal: &Term, name: N) -> Result<T>
-where
-	T: Display + FromStr + Clone,
-	N: AsRef<str>,
-	<T as FromStr>::Err: std::marker::Sync + std::error::Error + std::marker::Send + 'static,
-{
-	use dialoguer::Input;
-
-	let new = Input::<T>::with_theme(&ColorfulTheme::default())
-		.with_prompt(name.as_ref())
-		.interact_on(terminal)?;
-
-	Ok(new)
-}
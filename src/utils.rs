@@ -1,20 +1,25 @@
 use std::{
-	fs::File,
-	io::{BufReader, BufWriter},
+	collections::{HashMap, HashSet},
+	fmt::{self, Display},
+	fs::{create_dir_all, File},
+	io::{BufReader, Write},
 	net::Ipv4Addr,
-	path::PathBuf,
+	path::{Path, PathBuf},
+	str::FromStr,
 };
 
 use anyhow::{Context, Result};
-use chrono::{Duration, Utc};
+use bitflags::bitflags;
+use chrono::{DateTime, Duration, Local, Utc};
 
 use directories::ProjectDirs;
 use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use tempfile::NamedTempFile;
 use url::Url;
 
 use crate::{
-	constants::{APP_NAME, VERSION},
-	vpn::util::{Config, PlanTier},
+	constants::{APP_NAME, CLIENT_CONFIG_FILE, COUNTRY_CODES, SERVER_INFO_FILE, VERSION},
+	vpn::util::{Config, ConnectionProtocol},
 };
 
 /// This struct is for the `/vpn/logicals` API call. See [get_server()].
@@ -40,6 +45,113 @@ pub struct LogicalServer {
 	pub servers: Vec<Server>,
 	pub load: i16,
 	pub score: f64,
+	#[serde(default)]
+	pub features: Features,
+}
+
+bitflags! {
+	/// The `Features` bitmask of a [LogicalServer]. Bits the api adds later are dropped when deserializing.
+	#[derive(Serialize, Deserialize, Default)]
+	#[serde(from = "u32", into = "u32")]
+	pub struct Features: u32 {
+		/// Traffic enters through a hardened server in a privacy friendly country before exiting elsewhere
+		const SECURE_CORE = 1;
+		/// Traffic is routed through the Tor network
+		const TOR = 1 << 1;
+		/// Peer to peer / torrenting is allowed
+		const P2P = 1 << 2;
+		/// Optimized for streaming services
+		const STREAMING = 1 << 3;
+		/// Supports IPv6 inside the tunnel
+		const IPV6 = 1 << 4;
+	}
+}
+
+impl From<u32> for Features {
+	fn from(bits: u32) -> Self {
+		Self::from_bits_truncate(bits)
+	}
+}
+
+impl From<Features> for u32 {
+	fn from(features: Features) -> Self {
+		features.bits()
+	}
+}
+
+impl Features {
+	const NAMES: [(Features, &'static str); 5] = [
+		(Features::SECURE_CORE, "Secure-Core"),
+		(Features::TOR, "Tor"),
+		(Features::P2P, "P2P"),
+		(Features::STREAMING, "streaming"),
+		(Features::IPV6, "IPv6"),
+	];
+}
+
+/// Parses a single feature name, like `p2p` or `secure-core`. Case insensitive.
+impl FromStr for Features {
+	type Err = String;
+	fn from_str(s: &str) -> Result<Self, Self::Err> {
+		let normalized = s.trim().to_ascii_lowercase().replace(&['-', '_'][..], "");
+		Self::NAMES
+			.iter()
+			.find(|(_, name)| name.to_ascii_lowercase().replace('-', "") == normalized)
+			.map(|(feature, _)| *feature)
+			.ok_or_else(|| {
+				format!(
+					"Unknown feature {}. Must be one of secure-core, tor, p2p, streaming or ipv6",
+					s
+				)
+			})
+	}
+}
+
+/// Lists the set features, like `P2P and Tor`
+impl Display for Features {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		let names: Vec<_> = Self::NAMES
+			.iter()
+			.filter(|(feature, _)| self.contains(*feature))
+			.map(|(_, name)| *name)
+			.collect();
+		write!(f, "{}", names.join(" and "))
+	}
+}
+
+impl LogicalServer {
+	/// Whether this is a multi-hop server, entering in a different country than it exits
+	pub fn is_secure_core(&self) -> bool {
+		self.features.contains(Features::SECURE_CORE) && self.entry_country != self.exit_country
+	}
+}
+
+#[cfg(test)]
+impl LogicalServer {
+	/// Builds a server with a single entry ip, for tests that don't hit the api
+	pub(crate) fn mock(name: &str, tier: u8, score: f64, load: i16) -> Self {
+		let country = name[..2].to_string();
+		Self {
+			name: name.into(),
+			entry_country: country.clone(),
+			exit_country: country,
+			domain: format!("{}.protonvpn.com", name.to_ascii_lowercase()),
+			tier,
+			id: name.into(),
+			status: 1,
+			servers: vec![Server {
+				entry_ip: Ipv4Addr::LOCALHOST,
+				exit_ip: Ipv4Addr::LOCALHOST,
+				domain: format!("{}.protonvpn.com", name.to_ascii_lowercase()),
+				id: name.into(),
+				status: 1,
+				x25519_public_key: None,
+			}],
+			load,
+			score,
+			features: Features::empty(),
+		}
+	}
 }
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -53,14 +165,17 @@ pub struct Server {
 	#[serde(rename = "ID")]
 	pub id: String,
 	pub status: i8,
+	/// Base64 public key for wireguard. Missing on servers without wireguard.
+	#[serde(rename = "X25519PublicKey", default)]
+	pub x25519_public_key: Option<String>,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
 pub struct IpInfo {
 	#[serde(rename = "IP")]
-	ip: Ipv4Addr,
+	pub ip: Ipv4Addr,
 	#[serde(rename = "ISP")]
-	isp: String,
+	pub isp: String,
 }
 
 /// This function adds the protonvpn api headers and deserializes the response.
@@ -77,42 +192,230 @@ where
 		.context("couldn't deserialize api response")
 }
 
-/// Calls the protonvpn api endpoint `/vpn/logicals`, and stores the result in the [server info file](#crate::vpn::constants::SERVER_INFO_FILE). Returns servers that are available to the user are currently up.
+/// Calls the protonvpn api endpoint `/vpn/logicals`, and stores the result in the [server info file](crate::constants::SERVER_INFO_FILE). Returns every server, regardless of tier or status.
+///
+/// A fresh cache is used without calling the api. If the api can't be reached, the cached list is used no matter how old it is, with a warning on stderr. A download updates [Config::metadata], so the caller has to store the config afterwards.
 pub fn get_servers(config: &mut Config, pdir: &ProjectDirs) -> Result<Vec<LogicalServer>> {
-	let file_path = config_path(pdir, "servers.json");
-
-	// If its been at least 15 mins since the last server check
-	let now = Utc::now();
-	let mut servers_resp: ServersResponse;
-	if now - config.metadata.last_api_pull > Duration::minutes(15) {
-		// Download the list of servers
-		servers_resp = call_endpoint({
-			config.user.api_domain.set_path("vpn/logicals");
-			&config.user.api_domain
+	let file_path = cache_path(pdir, SERVER_INFO_FILE);
+	let expired = cache_expired(config, Utc::now());
+	servers_with_fallback(expired, &file_path, || download_servers(config, &file_path))
+}
+
+/// Tries the cache (if it hasn't `expired`), then `download`, then the cache again regardless of its age.
+fn servers_with_fallback<F>(
+	expired: bool,
+	file_path: &Path,
+	download: F,
+) -> Result<Vec<LogicalServer>>
+where
+	F: FnOnce() -> Result<Vec<LogicalServer>>,
+{
+	if !expired {
+		// A missing or corrupt cache is treated like an expired one
+		if let Ok(resp) = read_json::<ServersResponse>(file_path) {
+			return Ok(resp.logical_servers);
+		}
+	}
+	let api_err = match download() {
+		Ok(servers) => return Ok(servers),
+		Err(e) => e,
+	};
+	let cached =
+		match read_json::<ServersResponse>(file_path) {
+			Ok(resp) => resp.logical_servers,
+			Err(_) => return Err(api_err.context(
+				"Couldn't download the server list, and there is no cached copy to fall back on",
+			)),
+		};
+	let saved = file_path
+		.metadata()
+		.and_then(|m| m.modified())
+		.map(|t| {
+			DateTime::<Local>::from(t)
+				.format("%Y-%m-%d %H:%M")
+				.to_string()
 		})
+		.unwrap_or_else(|_| "an unknown time".into());
+	eprintln!(
+		"Warning: couldn't download the server list ({:#}). Using the cached list from {}, which may be out of date.",
+		api_err, saved
+	);
+	Ok(cached)
+}
+
+/// Downloads the server list and client config even if the cached copies are fresh. Returns how the server list changed.
+pub fn refresh(config: &mut Config, pdir: &ProjectDirs) -> Result<ServerDiff> {
+	let file_path = cache_path(pdir, SERVER_INFO_FILE);
+	// A missing or unreadable cache just means every server is new
+	let old = read_json::<ServersResponse>(&file_path)
+		.map(|resp| resp.logical_servers)
+		.unwrap_or_default();
+	let new = download_servers(config, &file_path)?;
+
+	let client_config: ClientConfig = call_endpoint(&api_url(config, "vpn/clientconfig"))
+		.context("failed to call vpn/clientconfig endpoint")?;
+	write_json(&cache_path(pdir, CLIENT_CONFIG_FILE), &client_config)?;
+
+	Ok(ServerDiff::between(&old, &new))
+}
+
+/// Whether the cached server list is older than [server_cache_minutes](crate::vpn::util::UserConfig::server_cache_minutes) minutes
+fn cache_expired(config: &Config, now: DateTime<Utc>) -> bool {
+	now - config.metadata.last_api_pull > Duration::minutes(config.user.server_cache_minutes.into())
+}
+
+/// Calls `/vpn/logicals` and caches the response at `file_path`. The new pull time goes into `config.metadata`, which [main](crate::main) saves even if the command fails later on.
+fn download_servers(config: &mut Config, file_path: &Path) -> Result<Vec<LogicalServer>> {
+	let servers_resp: ServersResponse = call_endpoint(&api_url(config, "vpn/logicals"))
 		.context("failed to call vpn/logicals endpoint")?;
+	write_json(file_path, &servers_resp)?;
+	config.metadata.last_api_pull = Utc::now();
+	Ok(servers_resp.logical_servers)
+}
+
+/// The cached `/vpn/clientconfig` response, or the defaults if `refresh` was never run
+pub fn get_client_config(pdir: &ProjectDirs) -> ClientConfig {
+	read_json(&cache_path(pdir, CLIENT_CONFIG_FILE)).unwrap_or_default()
+}
+
+/// Saves `config` to the file [confy::load] reads it from
+pub fn store_config(config: &Config) -> Result<()> {
+	let path = ProjectDirs::from("rs", "", APP_NAME)
+		.context("Couldn't find project dirs")?
+		.config_dir()
+		.join(format!("{}.toml", APP_NAME));
+	store_config_path(&path, config)
+}
+
+fn store_config_path(path: &Path, config: &Config) -> Result<()> {
+	let toml = toml::to_string_pretty(config).context("Couldn't serialize your configuration")?;
+	write_atomic(path, toml.as_bytes()).context("Couldn't store your configuration")
+}
+
+/// Writes to a temporary file next to `path`, then renames it over `path`. Readers see either the old or the new contents, never a partial write. Like any tempfile, the result is only readable by its owner.
+pub(crate) fn write_atomic(path: &Path, contents: &[u8]) -> Result<()> {
+	let dir = path.parent().context("path has no parent directory")?;
+	create_dir_all(dir).with_context(|| format!("Couldn't create {}", dir.display()))?;
+	let mut file = NamedTempFile::new_in(dir)?;
+	file.write_all(contents)?;
+	file.as_file().sync_all()?;
+	file.persist(path)
+		.with_context(|| format!("Couldn't write {}", path.display()))?;
+	Ok(())
+}
+
+fn read_json<T>(path: &Path) -> Result<T>
+where
+	T: DeserializeOwned,
+{
+	let file = File::open(path).with_context(|| format!("Couldn't open {}", path.display()))?;
+	serde_json::from_reader(BufReader::new(file))
+		.with_context(|| format!("Couldn't parse {}", path.display()))
+}
+
+fn write_json<T>(path: &Path, value: &T) -> Result<()>
+where
+	T: Serialize,
+{
+	write_atomic(path, &serde_json::to_vec(value)?)
+}
+
+fn api_url(config: &Config, path: &str) -> Url {
+	let mut url = config.user.api_domain.clone();
+	url.set_path(path);
+	url
+}
 
-		// Write them to the file
-		let server_info_file = BufWriter::new(File::create(file_path)?);
-		serde_json::to_writer(server_info_file, &servers_resp)?;
+/// How the server list changed between two downloads
+#[derive(Debug, Default, PartialEq)]
+pub struct ServerDiff {
+	pub added: usize,
+	pub removed: usize,
+	/// Servers in both lists that went online or offline
+	pub status_changed: usize,
+}
+
+impl ServerDiff {
+	pub fn between(old: &[LogicalServer], new: &[LogicalServer]) -> Self {
+		let old_status: HashMap<_, _> = old.iter().map(|s| (&s.id, s.status)).collect();
+		let new_ids: HashSet<_> = new.iter().map(|s| &s.id).collect();
 
-		config.metadata.last_api_pull = now;
-	} else {
-		let server_info_file = BufReader::new(File::open(file_path)?);
-		servers_resp = serde_json::from_reader(server_info_file)?;
+		let mut diff = Self {
+			removed: old_status
+				.keys()
+				.filter(|id| !new_ids.contains(*id))
+				.count(),
+			..Default::default()
+		};
+		for server in new {
+			match old_status.get(&server.id) {
+				None => diff.added += 1,
+				Some(status) if *status != server.status => diff.status_changed += 1,
+				Some(_) => {}
+			}
+		}
+		diff
+	}
+}
+
+impl Display for ServerDiff {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		write!(
+			f,
+			"{} servers added, {} removed, {} changed status",
+			self.added, self.removed, self.status_changed
+		)
+	}
+}
+
+/// This struct is for the `/vpn/clientconfig` API call. Only the fields this crate uses are kept.
+#[derive(Serialize, Deserialize, Debug, Default)]
+#[serde(rename_all = "PascalCase")]
+pub struct ClientConfig {
+	default_ports: DefaultPorts,
+}
+
+#[derive(Serialize, Deserialize, Debug, Default)]
+struct DefaultPorts {
+	#[serde(rename = "OpenVPN")]
+	openvpn: OpenVpnPorts,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct OpenVpnPorts {
+	#[serde(rename = "UDP")]
+	udp: Vec<u16>,
+	#[serde(rename = "TCP")]
+	tcp: Vec<u16>,
+}
+
+impl Default for OpenVpnPorts {
+	fn default() -> Self {
+		Self {
+			udp: vec![1194],
+			tcp: vec![443],
+		}
+	}
+}
+
+impl ClientConfig {
+	/// The ports openvpn servers listen on for `protocol`
+	pub fn openvpn_ports(&self, protocol: ConnectionProtocol) -> &[u16] {
+		match protocol {
+			ConnectionProtocol::UDP => &self.default_ports.openvpn.udp,
+			ConnectionProtocol::TCP => &self.default_ports.openvpn.tcp,
+		}
 	}
-	servers_resp
-		.logical_servers
-		.retain(|it| PlanTier::from(it.tier) <= config.user.tier && it.status == 1);
-	Ok(servers_resp.logical_servers)
 }
 
 /// Return the current public IP Address
 pub fn ip_info(config: &Config) -> Result<IpInfo> {
-	let mut url = config.user.api_domain.clone();
-	url.set_path("/vpn/location");
-	let resp = call_endpoint::<IpInfo>(&url)?;
-	Ok(resp)
+	call_endpoint(&api_url(config, "vpn/location"))
+}
+
+/// Full country name for `cc`, or `cc` itself if it isn't in [COUNTRY_CODES]
+pub fn country_name(cc: &str) -> &str {
+	COUNTRY_CODES.get(cc).map_or(cc, String::as_str)
 }
 
 pub fn config_path<S>(pdir: &ProjectDirs, filename: S) -> PathBuf
@@ -124,6 +427,14 @@ where
 	config_path
 }
 
+/// Like [config_path], but in the cache dir. Downloaded data goes here, since it can always be fetched again.
+pub fn cache_path<S>(pdir: &ProjectDirs, filename: S) -> PathBuf
+where
+	S: AsRef<str>,
+{
+	pdir.cache_dir().join(filename.as_ref())
+}
+
 pub fn project_dirs() -> ProjectDirs {
 	ProjectDirs::from("io.github.hybras", "", APP_NAME)
 		.context("Couldn't find project dirs")
@@ -145,9 +456,143 @@ mod tests {
 		assert!(t.is_err());
 	}
 
+	#[test]
+	fn test_features_deserialize() {
+		let features: Features = serde_json::from_str("5").unwrap();
+		assert_eq!(features, Features::SECURE_CORE | Features::P2P);
+
+		let features: Features = serde_json::from_str("1032").unwrap();
+		assert_eq!(features, Features::STREAMING);
+
+		assert_eq!(serde_json::to_string(&Features::TOR).unwrap(), "2");
+	}
+
+	#[test]
+	fn test_features_from_str() {
+		assert_eq!("p2p".parse(), Ok(Features::P2P));
+		assert_eq!("Secure-Core".parse(), Ok(Features::SECURE_CORE));
+		assert_eq!("secure_core".parse(), Ok(Features::SECURE_CORE));
+		assert!("netflix".parse::<Features>().is_err());
+
+		assert_eq!((Features::P2P | Features::TOR).to_string(), "Tor and P2P");
+	}
+
+	#[test]
+	fn test_cache_expired() {
+		let mut config = Config::default();
+		config.user.server_cache_minutes = 30;
+		let now = Utc::now();
+		config.metadata.last_api_pull = now - Duration::minutes(20);
+		assert!(!cache_expired(&config, now));
+		config.metadata.last_api_pull = now - Duration::minutes(40);
+		assert!(cache_expired(&config, now));
+	}
+
+	#[test]
+	fn test_store_config() {
+		let dir = tempfile::tempdir().unwrap();
+		let path = dir.path().join("nested").join("config.toml");
+		let mut config = Config::default();
+		store_config_path(&path, &config).unwrap();
+
+		config.metadata.last_api_pull = Utc::now();
+		store_config_path(&path, &config).unwrap();
+		let loaded: Config = confy::load_path(&path).unwrap();
+		assert_eq!(loaded.metadata.last_api_pull, config.metadata.last_api_pull);
+		// Only the config itself is left, no temp files
+		assert_eq!(
+			std::fs::read_dir(path.parent().unwrap()).unwrap().count(),
+			1
+		);
+	}
+
+	#[test]
+	fn test_servers_with_fallback() {
+		let dir = tempfile::tempdir().unwrap();
+		let path = dir.path().join(SERVER_INFO_FILE);
+		let offline = || Err(anyhow::anyhow!("api unreachable"));
+		let online = || Ok(vec![LogicalServer::mock("SE#2", 2, 1.0, 10)]);
+		let names =
+			|servers: Vec<LogicalServer>| servers.into_iter().map(|s| s.name).collect::<Vec<_>>();
+
+		// Nothing cached and no api
+		let err = servers_with_fallback(false, &path, offline).unwrap_err();
+		assert!(format!("{:#}", err).contains("no cached copy"));
+		assert!(format!("{:#}", err).contains("api unreachable"));
+
+		// A missing cache falls through to the api, even if it isn't expired
+		assert_eq!(
+			names(servers_with_fallback(false, &path, online).unwrap()),
+			["SE#2"]
+		);
+
+		let cached = ServersResponse {
+			code: 1000,
+			logical_servers: vec![LogicalServer::mock("SE#1", 2, 1.0, 10)],
+		};
+		write_json(&path, &cached).unwrap();
+
+		// Fresh cache wins over the api
+		assert_eq!(
+			names(servers_with_fallback(false, &path, online).unwrap()),
+			["SE#1"]
+		);
+		// Expired cache is only used when the api is down
+		assert_eq!(
+			names(servers_with_fallback(true, &path, online).unwrap()),
+			["SE#2"]
+		);
+		assert_eq!(
+			names(servers_with_fallback(true, &path, offline).unwrap()),
+			["SE#1"]
+		);
+	}
+
+	#[test]
+	fn test_server_diff() {
+		let mut offline = LogicalServer::mock("CH#2", 2, 1.0, 10);
+		offline.status = 0;
+		let old = vec![
+			LogicalServer::mock("CH#1", 2, 1.0, 10),
+			LogicalServer::mock("CH#2", 2, 1.0, 10),
+			LogicalServer::mock("CH#3", 2, 1.0, 10),
+		];
+		let new = vec![
+			LogicalServer::mock("CH#1", 2, 1.0, 90),
+			offline,
+			LogicalServer::mock("CH#4", 2, 1.0, 10),
+			LogicalServer::mock("CH#5", 2, 1.0, 10),
+		];
+
+		let diff = ServerDiff::between(&old, &new);
+		assert_eq!(
+			diff,
+			ServerDiff {
+				added: 2,
+				removed: 1,
+				status_changed: 1
+			}
+		);
+		assert_eq!(
+			diff.to_string(),
+			"2 servers added, 1 removed, 1 changed status"
+		);
+	}
+
+	#[test]
+	fn test_client_config() {
+		let resp = r#"{"Code":1000,"DefaultPorts":{"OpenVPN":{"UDP":[80,1194],"TCP":[443,7770]},"IKEv2":{}},"ServerRefreshInterval":10}"#;
+		let config: ClientConfig = serde_json::from_str(resp).unwrap();
+		assert_eq!(config.openvpn_ports(ConnectionProtocol::UDP), &[80, 1194]);
+		assert_eq!(config.openvpn_ports(ConnectionProtocol::TCP), &[443, 7770]);
+
+		let config = ClientConfig::default();
+		assert_eq!(config.openvpn_ports(ConnectionProtocol::UDP), &[1194]);
+	}
+
 	#[test]
 	fn test_ip_info() -> Result<()> {
-		let _ip_info = ip_info(&mut Default::default())?;
+		let _ip_info = ip_info(&Default::default())?;
 		Ok(())
 	}
 }
@@ -1,39 +1,103 @@
 use std::{
-	fs::File,
-	io::{BufRead, BufReader, BufWriter, Write},
+	fs::{read_to_string, remove_file, File},
+	io::{BufWriter, ErrorKind, Write},
 	net::Ipv4Addr,
-	path::Path,
+	path::{Path, PathBuf},
 	process::{Child, Command, Stdio},
+	thread::sleep,
+	time::{Duration, Instant},
 };
 
-use anyhow::{Context, Result};
+use anyhow::{anyhow, bail, Context, Result};
 use askama::Template;
+use backend::{Handle, TunnelStatus, VpnBackend};
+use directories::ProjectDirs;
+use dns::pushed_dns;
+use management::{ManagementClient, OpenVpnSignal};
+use nix::{
+	sys::signal::{kill, Signal},
+	unistd::Pid,
+};
 use serde::{Deserialize, Serialize};
-use serde_json::from_reader;
-use tempfile::{NamedTempFile, TempPath};
-use util::{ConnectionProtocol, UserConfig};
+use util::{Backend, ConnectionInfo, ConnectionProtocol, UserConfig};
 
-use crate::utils::LogicalServer;
+use crate::{
+	constants::{MANAGEMENT_SOCKET, OVPN_FILE, OVPN_LOG, TUN_DEVICE, WG_DEVICE},
+	secrets::{self, SecretStore},
+	utils::{config_path, LogicalServer},
+};
 
+/// Bringing tunnels up and down, whatever the vpn software.
+pub mod backend;
+/// Running external programs in a way tests can fake.
+pub(crate) mod command;
+/// Dns leak protection.
+pub mod dns;
+/// The nftables kill switch.
+pub mod firewall;
+/// Ipv6 leak protection.
+pub(crate) mod ipv6;
+/// Talking to a running openvpn process through its management socket.
+pub mod management;
+/// The list of networks that bypass the vpn.
+pub mod split_tunnel;
 /// This module declares all the structs that store application state.
 pub mod util;
+/// Connecting through wireguard with wg-quick.
+pub mod wireguard;
+
+/// What goes into the generated openvpn or wireguard config, besides the server
+#[derive(Debug, Clone)]
+pub struct TunnelSettings {
+	pub(crate) backend: Backend,
+	/// Always UDP for wireguard
+	pub(crate) protocol: ConnectionProtocol,
+	/// Tried in random order, see [ClientConfig::openvpn_ports](crate::utils::ClientConfig::openvpn_ports)
+	pub(crate) ports: Vec<u16>,
+	/// Ignore the ipv6 settings the server pushes. See [ipv6::Ipv6Plan].
+	pub(crate) ipv6_disabled: bool,
+	/// Networks from the [split tunnel list](split_tunnel::SplitTunnelList)
+	pub(crate) split_tunnel: Vec<IpNm>,
+	/// Whether [split_tunnel](Self::split_tunnel) bypasses the vpn or is the only thing routed through it
+	pub(crate) split_mode: split_tunnel::SplitMode,
+}
+
+impl TunnelSettings {
+	/// The interface the tunnel comes up on
+	pub(crate) fn interface(&self) -> &'static str {
+		match self.backend {
+			Backend::OpenVpn => TUN_DEVICE,
+			Backend::WireGuard => WG_DEVICE,
+		}
+	}
+
+	/// Like `UDP`, or `WireGuard`, which always runs over UDP
+	pub(crate) fn transport(&self) -> String {
+		match self.backend {
+			Backend::OpenVpn => self.protocol.to_string(),
+			Backend::WireGuard => self.backend.to_string(),
+		}
+	}
+}
 
 #[derive(Template)] // this will generate the code...
 #[template(path = "openvpn_template.j2")]
 struct OpenVpnConfig {
 	openvpn_protocol: ConnectionProtocol,
 	server_list: Vec<Ipv4Addr>,
-	openvpn_ports: Vec<usize>,
+	openvpn_ports: Vec<u16>,
 	/// Whether to use split tunnel or not
 	split: bool,
+	/// Ignore the server's routes and only route `ip_nm_pairs` through the vpn, instead of around it
+	include_only: bool,
 	ip_nm_pairs: Vec<IpNm>,
 	/// Use ipv6 and fallback to ipv4, or only use ipv4. Usefull for older devices and networks
 	ipv6_disabled: bool,
 }
 
 /// An IPv4 address and a netmask.
-#[derive(Serialize, Deserialize, PartialEq, Eq, Debug)]
-struct IpNm {
+#[derive(Serialize, Deserialize, PartialEq, Eq, Debug, Clone)]
+pub(crate) struct IpNm {
 	ip: Ipv4Addr,
 	#[serde(default = "IpNm::default_netmask")]
 	nm: Ipv4Addr,
@@ -45,42 +109,236 @@ impl IpNm {
 	}
 }
 
-/// Stores information about the current connection. Its purpose to prevent the passfile path from being dropped (upon drop the corresponding file is unlinked/deleted)
-pub struct VpnConnection {
-	pub(crate) openvpn_process: Child,
-	pub(crate) passfile: TempPath,
+/// A freshly started openvpn process
+struct VpnConnection {
+	openvpn_process: Child,
+	management_socket: PathBuf,
+}
+
+impl VpnConnection {
+	/// Lets openvpn keep running after this process exits
+	fn detach(self, server: &LogicalServer, protocol: ConnectionProtocol) -> ConnectionInfo {
+		ConnectionInfo::new(
+			server,
+			protocol,
+			TUN_DEVICE,
+			Handle::OpenVpn {
+				pid: self.openvpn_process.id(),
+				management_socket: self.management_socket,
+			},
+		)
+	}
+}
+
+/// What openvpn logs in with. Only ever handed to openvpn through its management socket, never written to disk.
+struct Credentials {
+	username: String,
+	password: String,
+}
+
+/// Runs openvpn with a generated config. Openvpn is detached once it is started, so the tunnel outlives the cli.
+pub(crate) struct OpenVpn {
+	config_path: PathBuf,
+	log_path: PathBuf,
+	management_socket: PathBuf,
+	pdir: ProjectDirs,
+	/// Loaded by [prepare](VpnBackend::prepare), handed to openvpn by [up](VpnBackend::up)
+	credentials: Option<Credentials>,
+}
+
+impl OpenVpn {
+	/// Keeps the config, log and management socket in the app's config dir
+	pub(crate) fn new(pdir: &ProjectDirs) -> Self {
+		Self {
+			config_path: config_path(pdir, OVPN_FILE),
+			log_path: config_path(pdir, OVPN_LOG),
+			management_socket: config_path(pdir, MANAGEMENT_SOCKET),
+			pdir: pdir.clone(),
+			credentials: None,
+		}
+	}
+
+	/// Starts openvpn on hold. It asks for the credentials through the management socket once released, see [log_in](Self::log_in).
+	fn spawn(&self) -> Result<VpnConnection> {
+		let stdout = File::create(&self.log_path)?;
+		let stderr = stdout.try_clone()?;
+		remove_if_exists(&self.management_socket)?;
+
+		let cmd = Command::new("openvpn")
+			.arg("--config")
+			.arg(&self.config_path)
+			.arg("--dev")
+			.arg(TUN_DEVICE)
+			.arg("--dev-type")
+			.arg("tun")
+			.arg("--management")
+			.arg(&self.management_socket)
+			.arg("unix")
+			.arg("--management-query-passwords")
+			.arg("--management-hold")
+			.stdin(Stdio::null())
+			.stdout(stdout)
+			.stderr(stderr)
+			.spawn()
+			.context("couldn't spawn openvpn")?;
+
+		Ok(VpnConnection {
+			openvpn_process: cmd,
+			management_socket: self.management_socket.clone(),
+		})
+	}
+
+	/// Waits for the management socket of openvpn `pid` to come up, then releases the hold and answers the request for credentials
+	fn log_in(&self, pid: u32, credentials: &Credentials) -> Result<()> {
+		let start = Instant::now();
+		let mut client = loop {
+			match ManagementClient::connect(&self.management_socket) {
+				Ok(client) => break client,
+				Err(e) if !is_running(pid) || start.elapsed() >= CONNECT_TIMEOUT => {
+					return Err(e.context(format!(
+						"openvpn didn't open its management socket. See {} for details",
+						self.log_path.display()
+					)))
+				}
+				Err(_) => sleep(Duration::from_millis(100)),
+			}
+		};
+		client.log_in(&credentials.username, &credentials.password)
+	}
+
+	/// Waits until openvpn reports that the tunnel is up. Fails early if openvpn exits, pointing at its log.
+	fn wait_until_connected(&self, info: &ConnectionInfo, pid: u32) -> Result<()> {
+		let start = Instant::now();
+		while start.elapsed() < CONNECT_TIMEOUT {
+			if !is_running(pid) {
+				return Err(anyhow!(
+					"openvpn exited before connecting. See {} for details",
+					self.log_path.display()
+				));
+			}
+			let state = ManagementClient::connect(&self.management_socket)
+				.and_then(|mut client| client.state());
+			if let Ok(state) = state {
+				if state.name == "CONNECTED" {
+					return Ok(());
+				}
+			}
+			sleep(Duration::from_millis(500));
+		}
+		Err(anyhow!(
+			"Timed out connecting to {}. See {} for details",
+			info.server_name,
+			self.log_path.display()
+		))
+	}
+}
+
+impl VpnBackend for OpenVpn {
+	fn prepare(
+		&mut self,
+		server: &LogicalServer,
+		settings: &TunnelSettings,
+		user: &UserConfig,
+	) -> Result<()> {
+		create_openvpn_config(
+			&server
+				.servers
+				.iter()
+				.map(|s| s.entry_ip)
+				.collect::<Vec<_>>(),
+			settings,
+			&mut File::create(&self.config_path)?,
+		)?;
+		let mut secrets = secrets::for_user(user, &self.pdir);
+		self.credentials = Some(load_credentials(user, &mut *secrets)?);
+		Ok(())
+	}
+
+	fn up(&mut self, server: &LogicalServer, settings: &TunnelSettings) -> Result<ConnectionInfo> {
+		let credentials = self
+			.credentials
+			.take()
+			.context("The openvpn connection wasn't prepared")?;
+		let connection = self.spawn()?;
+		let pid = connection.openvpn_process.id();
+		let info = connection.detach(server, settings.protocol);
+		let connected = self
+			.log_in(pid, &credentials)
+			.and_then(|()| self.wait_until_connected(&info, pid));
+		match connected {
+			Ok(()) => Ok(info),
+			Err(e) => self.down(&info).and(Err(e)),
+		}
+	}
+
+	/// Openvpn is asked to exit through its management socket first, falling back to a unix signal if that doesn't work.
+	fn down(&mut self, info: &ConnectionInfo) -> Result<()> {
+		let (pid, management_socket) = match &info.handle {
+			Handle::OpenVpn {
+				pid,
+				management_socket,
+			} => (*pid, management_socket),
+			other => bail!("{:?} isn't an openvpn tunnel", other),
+		};
+		if is_running(pid) {
+			let signalled = ManagementClient::connect(management_socket)
+				.and_then(|mut client| client.signal(OpenVpnSignal::SIGTERM))
+				.is_ok();
+			if signalled {
+				wait_or_kill(pid, STOP_TIMEOUT)?;
+			} else {
+				// The management socket is gone, or openvpn stopped answering on it
+				stop_process(pid, STOP_TIMEOUT)?;
+			}
+		}
+		remove_if_exists(&self.config_path)?;
+		remove_if_exists(management_socket)?;
+		Ok(())
+	}
+
+	fn status(&mut self, info: &ConnectionInfo) -> Option<TunnelStatus> {
+		let (pid, management_socket) = match &info.handle {
+			Handle::OpenVpn {
+				pid,
+				management_socket,
+				..
+			} => (*pid, management_socket),
+			_ => return None,
+		};
+		if !is_running(pid) {
+			return None;
+		}
+		// Openvpn knows the most, but the interface counters still work if the management socket doesn't
+		let mut management = ManagementClient::connect(management_socket).ok();
+		let state = management.as_mut().and_then(|client| client.state().ok());
+		let traffic = management
+			.as_mut()
+			.and_then(|client| client.bytecount().ok())
+			.or_else(|| traffic(&info.interface).ok());
+		Some(TunnelStatus { state, traffic })
+	}
+
+	fn offered_dns(&mut self, _info: &ConnectionInfo) -> Result<Vec<Ipv4Addr>> {
+		pushed_dns(&self.log_path)
+	}
 }
 
-fn create_openvpn_config<R, W>(
-	servers: &Vec<Ipv4Addr>,
-	protocol: &ConnectionProtocol,
-	ports: &Vec<usize>,
-	split_tunnel_file: Option<R>,
+fn create_openvpn_config<W>(
+	servers: &[Ipv4Addr],
+	settings: &TunnelSettings,
 	output_file: &mut W,
 ) -> Result<()>
 where
-	R: BufRead,
 	W: Write,
 {
-	let (ip_nm_pairs, split) = if let Some(split_tunnel_file) = split_tunnel_file {
-		(
-			from_reader(split_tunnel_file).context(
-				"Failed to deserialize split_tunnel_file. Please check that it is valid json",
-			)?,
-			true,
-		)
-	} else {
-		(vec![], false)
-	};
-
 	let ovpn_conf = OpenVpnConfig {
-		openvpn_protocol: *protocol,
-		server_list: servers.clone(),
-		openvpn_ports: ports.clone(),
-		split,
-		ip_nm_pairs,
-		// TODO check if ipv6 is actually disabled
-		ipv6_disabled: false,
+		openvpn_protocol: settings.protocol,
+		server_list: servers.to_vec(),
+		openvpn_ports: settings.ports.clone(),
+		split: !settings.split_tunnel.is_empty(),
+		include_only: settings.split_mode == split_tunnel::SplitMode::IncludeOnly,
+		ip_nm_pairs: settings.split_tunnel.clone(),
+		ipv6_disabled: settings.ipv6_disabled,
 	};
 
 	// TODO Use Template::render_into
@@ -92,108 +350,189 @@ where
 	Ok(())
 }
 
-fn connect_helper(
-	server: &LogicalServer,
-	protocol: &ConnectionProtocol,
-	passfile: TempPath,
-	config: &Path,
-	log: &Path,
-) -> Result<VpnConnection> {
-	create_openvpn_config::<BufReader<File>, File>(
-		&server.servers.iter().map(|s| s.entry_ip).collect(),
-		protocol,
-		&vec![match protocol {
-			ConnectionProtocol::TCP => 443,
-			ConnectionProtocol::UDP => 1194,
-		} as usize],
-		None,
-		&mut File::create(config)?,
-	)?;
-
-	let stdout = File::create(log)?;
-	let stderr = stdout.try_clone()?;
-
-	let cmd = Command::new("openvpn")
-		.arg("--config")
-		.arg(config)
-		.arg("--auth-user-pass")
-		.arg(&passfile)
-		.arg("--dev")
-		.arg("proton0")
-		.arg("--dev-type")
-		.arg("tun")
-		.stdin(Stdio::null())
-		.stdout(stdout)
-		.stderr(stderr)
-		.spawn()
-		.context("couldn't spawn openvpn")?;
-
-	let connection = VpnConnection {
-		openvpn_process: cmd,
-		passfile,
+/// Whether the openvpn process `pid` is still running
+fn is_running(pid: u32) -> bool {
+	process_name(pid).as_deref() == Some("openvpn")
+}
+
+/// Bytes received and sent through `interface` since it came up, read from sysfs
+pub fn traffic(interface: &str) -> Result<(u64, u64)> {
+	let read_counter = |name: &str| -> Result<u64> {
+		let path = format!("/sys/class/net/{}/statistics/{}", interface, name);
+		read_to_string(&path)
+			.with_context(|| format!("Couldn't read {}", path))?
+			.trim()
+			.parse()
+			.with_context(|| format!("{} isn't a number", path))
 	};
+	Ok((read_counter("rx_bytes")?, read_counter("tx_bytes")?))
+}
+
+/// How long openvpn gets to bring the tunnel up
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// How long openvpn gets to shut down after SIGTERM before it is killed
+const STOP_TIMEOUT: Duration = Duration::from_secs(5);
 
-	Ok(connection)
+/// Sends SIGTERM to `pid`, then SIGKILL if it is still running after `timeout`.
+fn stop_process(pid: u32, timeout: Duration) -> Result<()> {
+	terminate(pid)?;
+	wait_or_kill(pid, timeout)
 }
 
-/// This function wraps the helper, first creating the password tempfile and passing it in.
-pub fn connect(
-	server: &LogicalServer,
-	protocol: &ConnectionProtocol,
-	user_config: &UserConfig,
-	config_path: &Path,
-	log_path: &Path,
-) -> Result<VpnConnection> {
-	let pass_path = create_passfile(user_config)?;
-	connect_helper(server, protocol, pass_path, &config_path, &log_path)
+fn terminate(pid: u32) -> Result<()> {
+	kill(Pid::from_raw(pid as i32), Signal::SIGTERM)
+		.with_context(|| format!("Couldn't stop openvpn (pid {}). Are you root?", pid))
 }
 
-fn create_passfile(config: &UserConfig) -> Result<TempPath> {
-	let f = NamedTempFile::new()?;
-	let mut buf = BufWriter::new(f);
-	let client_suffix = "plc";
+/// Waits for `pid` to exit after being asked to, sending SIGKILL if it is still running after `timeout`.
+fn wait_or_kill(pid: u32, timeout: Duration) -> Result<()> {
+	if wait_for_exit(pid, timeout) {
+		return Ok(());
+	}
+
+	kill(Pid::from_raw(pid as i32), Signal::SIGKILL)
+		.with_context(|| format!("Couldn't kill openvpn (pid {})", pid))?;
+	if wait_for_exit(pid, Duration::from_secs(1)) {
+		Ok(())
+	} else {
+		Err(anyhow!("openvpn (pid {}) is still running", pid))
+	}
+}
+
+/// Polls until `pid` is gone or `timeout` passes. Returns whether it exited.
+fn wait_for_exit(pid: u32, timeout: Duration) -> bool {
+	let start = Instant::now();
+	while process_name(pid).is_some() {
+		if start.elapsed() >= timeout {
+			return false;
+		}
+		sleep(Duration::from_millis(50));
+	}
+	true
+}
+
+/// The command name of a running process, read from `/proc/<pid>/stat`. Exited (including zombie) processes return `None`.
+fn process_name(pid: u32) -> Option<String> {
+	let stat = read_to_string(format!("/proc/{}/stat", pid)).ok()?;
+	let name_start = stat.find('(')? + 1;
+	let name_end = stat.rfind(')')?;
+	let state = stat[name_end + 1..].trim_start().chars().next()?;
+	if state == 'Z' || state == 'X' {
+		None
+	} else {
+		Some(stat[name_start..name_end].to_string())
+	}
+}
+
+pub(crate) fn remove_if_exists(path: &Path) -> Result<()> {
+	match remove_file(path) {
+		Err(e) if e.kind() != ErrorKind::NotFound => {
+			Err(e).with_context(|| format!("Couldn't remove {}", path.display()))
+		}
+		_ => Ok(()),
+	}
+}
 
-	write!(
-		buf,
-		"{}+{}\n{}\n",
-		config.username, client_suffix, config.password
-	)?;
+/// The credentials openvpn logs in with. The password is read from `secrets`.
+fn load_credentials(config: &UserConfig, secrets: &mut dyn SecretStore) -> Result<Credentials> {
+	let password = secrets.load(&config.username)?;
+	let client_suffix = "plc";
 
-	Ok(buf.into_inner()?.into_temp_path())
+	Ok(Credentials {
+		username: format!("{}+{}", config.username, client_suffix),
+		password,
+	})
 }
 
 #[cfg(test)]
 mod tests {
-	use std::fs::read;
-
 	use super::*;
 
 	#[test]
 	fn test_create_ovpn_conf() -> Result<()> {
 		let mut output = vec![];
 
-		let res = create_openvpn_config::<BufReader<File>, Vec<u8>>(
-			&vec![Ipv4Addr::new(108, 59, 0, 40)],
-			&ConnectionProtocol::UDP,
-			&vec![1134],
-			None,
-			&mut output,
-		)?;
-		Ok(res)
+		let mut settings = TunnelSettings {
+			backend: Backend::OpenVpn,
+			protocol: ConnectionProtocol::UDP,
+			ports: vec![1134],
+			ipv6_disabled: false,
+			split_tunnel: vec![],
+			split_mode: split_tunnel::SplitMode::Exclude,
+		};
+		create_openvpn_config(&[Ipv4Addr::new(108, 59, 0, 40)], &settings, &mut output)?;
+		let config = String::from_utf8(output)?;
+		assert!(config.contains("remote 108.59.0.40 1134\n"));
+		assert!(!config.contains("pull-filter ignore \"route-ipv6\""));
+
+		settings.ipv6_disabled = true;
+		let mut output = vec![];
+		create_openvpn_config(&[Ipv4Addr::new(108, 59, 0, 40)], &settings, &mut output)?;
+		assert!(String::from_utf8(output)?.contains("pull-filter ignore \"route-ipv6\""));
+
+		settings.split_tunnel = vec!["192.168.0.0/16".parse::<split_tunnel::Cidr>()?.into()];
+		let mut output = vec![];
+		create_openvpn_config(&[Ipv4Addr::new(108, 59, 0, 40)], &settings, &mut output)?;
+		let config = String::from_utf8(output)?;
+		assert!(config.contains("route 192.168.0.0 255.255.0.0 net_gateway"));
+		assert!(!config.contains("route-nopull"));
+
+		settings.split_mode = split_tunnel::SplitMode::IncludeOnly;
+		let mut output = vec![];
+		create_openvpn_config(&[Ipv4Addr::new(108, 59, 0, 40)], &settings, &mut output)?;
+		let config = String::from_utf8(output)?;
+		assert!(config.contains("route-nopull\nroute 192.168.0.0 255.255.0.0\n"));
+		assert!(!config.contains("net_gateway"));
+		Ok(())
 	}
 
 	#[test]
-	fn test_passfile() -> Result<()> {
+	fn test_credentials() -> Result<()> {
 		let user = "user";
 		let pass = "pass";
-		let path = create_passfile(&UserConfig::new(user.into(), pass.into()))?;
+		let mut secrets = secrets::Memory::default();
+		let config = UserConfig::new(user.into());
+		assert!(load_credentials(&config, &mut secrets).is_err());
+		secrets.save(user, pass)?;
+		let credentials = load_credentials(&config, &mut secrets)?;
+		assert_eq!(credentials.username, "user+plc");
+		assert_eq!(credentials.password, pass);
 
-		let buf = read(&path)?;
-		let s = String::from_utf8(buf)?;
-		assert_eq!(s, format!("{}+plc\n{}\n", user, pass));
+		let mut command = secrets::PasswordCommand::new("printf 'from command\\n'".into());
+		let credentials = load_credentials(&config, &mut command)?;
+		assert_eq!(credentials.password, "from command");
+		Ok(())
+	}
 
-		let output = Command::new("/usr/bin/cat").arg(&path).output()?;
-		assert!(output.status.success());
+	#[test]
+	fn test_stop_process() -> Result<()> {
+		let mut child = Command::new("sleep").arg("30").spawn()?;
+		assert_eq!(process_name(child.id()).as_deref(), Some("sleep"));
+
+		stop_process(child.id(), Duration::from_secs(5))?;
+		assert!(!child.wait()?.success());
+		assert_eq!(process_name(child.id()), None);
+		Ok(())
+	}
+
+	#[test]
+	fn test_stop_process_escalates() -> Result<()> {
+		let mut child = Command::new("sh")
+			.arg("-c")
+			.arg("trap '' TERM; while true; do sleep 0.1; done")
+			.spawn()?;
+		sleep(Duration::from_millis(200));
+
+		stop_process(child.id(), Duration::from_millis(300))?;
+		assert!(!child.wait()?.success());
+		Ok(())
+	}
+
+	#[test]
+	fn test_traffic() -> Result<()> {
+		traffic("lo")?;
+		assert!(traffic("does-not-exist").is_err());
 		Ok(())
 	}
 
@@ -0,0 +1,207 @@
+use std::{net::Ipv4Addr, path::PathBuf};
+
+use anyhow::Result;
+use directories::ProjectDirs;
+use serde::{Deserialize, Serialize};
+
+use super::{
+	command::SystemRunner,
+	management::State,
+	util::{Backend, ConnectionInfo, UserConfig},
+	wireguard::WireGuard,
+	OpenVpn, TunnelSettings,
+};
+use crate::utils::LogicalServer;
+
+/// Brings tunnels up and down. The cli only talks to tunnels through this, so tests can use [Scripted] instead of running openvpn as root.
+pub(crate) trait VpnBackend {
+	/// Writes what a tunnel to `server` needs, like config and credential files. Nothing is started yet.
+	fn prepare(
+		&mut self,
+		server: &LogicalServer,
+		settings: &TunnelSettings,
+		user: &UserConfig,
+	) -> Result<()>;
+	/// Starts the tunnel [prepare](VpnBackend::prepare)d last and waits until it is connected. If that fails, nothing is left running. The returned info is what gets persisted in [Config](super::util::Config).
+	fn up(&mut self, server: &LogicalServer, settings: &TunnelSettings) -> Result<ConnectionInfo>;
+	/// Takes the tunnel down and removes the files it left behind. A tunnel that already went down is not an error.
+	fn down(&mut self, info: &ConnectionInfo) -> Result<()>;
+	/// What is known about the tunnel, or `None` if it went down
+	fn status(&mut self, info: &ConnectionInfo) -> Option<TunnelStatus>;
+	/// The dns servers the vpn server offers, used for dns leak protection unless there is custom dns
+	fn offered_dns(&mut self, info: &ConnectionInfo) -> Result<Vec<Ipv4Addr>>;
+}
+
+/// What a backend needs to find a tunnel again, from a later run of the cli. Each backend only accepts its own variant.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(untagged)]
+pub enum Handle {
+	/// A detached openvpn process
+	OpenVpn {
+		/// Process id of openvpn
+		pid: u32,
+		/// Unix socket of openvpn's management interface, see [ManagementClient](super::management::ManagementClient)
+		management_socket: PathBuf,
+	},
+	/// An interface brought up by wg-quick
+	WireGuard {
+		/// The wg-quick config, which holds the private key
+		config: PathBuf,
+	},
+	/// A tunnel of the [Scripted] backend
+	#[cfg(test)]
+	Scripted {
+		/// Tells the scripted tunnels apart
+		scripted: u32,
+	},
+}
+
+/// A tunnel that is still up. Either part may be unknown.
+#[derive(Debug, Default, PartialEq)]
+pub(crate) struct TunnelStatus {
+	/// Openvpn's state, from its management interface
+	pub(crate) state: Option<State>,
+	/// Bytes received and sent
+	pub(crate) traffic: Option<(u64, u64)>,
+}
+
+/// Uses openvpn or wireguard: the [Backend] setting decides for new tunnels, the [Handle] for running ones
+pub(crate) struct SystemBackend {
+	openvpn: OpenVpn,
+	wireguard: WireGuard<SystemRunner>,
+	/// What the last [prepare](VpnBackend::prepare) was for
+	prepared: Backend,
+}
+
+impl SystemBackend {
+	/// Keeps configs, keys and logs in the app's config dir
+	pub(crate) fn new(pdir: &ProjectDirs) -> Self {
+		Self {
+			openvpn: OpenVpn::new(pdir),
+			wireguard: WireGuard::new(pdir, SystemRunner),
+			prepared: Backend::OpenVpn,
+		}
+	}
+
+	fn for_handle(&mut self, handle: &Handle) -> &mut dyn VpnBackend {
+		match handle {
+			Handle::WireGuard { .. } => &mut self.wireguard,
+			_ => &mut self.openvpn,
+		}
+	}
+}
+
+impl VpnBackend for SystemBackend {
+	fn prepare(
+		&mut self,
+		server: &LogicalServer,
+		settings: &TunnelSettings,
+		user: &UserConfig,
+	) -> Result<()> {
+		self.prepared = settings.backend;
+		match settings.backend {
+			Backend::OpenVpn => self.openvpn.prepare(server, settings, user),
+			Backend::WireGuard => self.wireguard.prepare(server, settings, user),
+		}
+	}
+
+	fn up(&mut self, server: &LogicalServer, settings: &TunnelSettings) -> Result<ConnectionInfo> {
+		match self.prepared {
+			Backend::OpenVpn => self.openvpn.up(server, settings),
+			Backend::WireGuard => self.wireguard.up(server, settings),
+		}
+	}
+
+	fn down(&mut self, info: &ConnectionInfo) -> Result<()> {
+		self.for_handle(&info.handle).down(info)
+	}
+
+	fn status(&mut self, info: &ConnectionInfo) -> Option<TunnelStatus> {
+		self.for_handle(&info.handle).status(info)
+	}
+
+	fn offered_dns(&mut self, info: &ConnectionInfo) -> Result<Vec<Ipv4Addr>> {
+		self.for_handle(&info.handle).offered_dns(info)
+	}
+}
+
+/// A backend for tests that doesn't bring anything up. Every call is recorded in `events`. Tunnels stay up until they are taken down, or removed from `running` to simulate a crash.
+#[cfg(test)]
+#[derive(Debug, Default)]
+pub(crate) struct Scripted {
+	pub(crate) events: Vec<String>,
+	/// Makes [up](VpnBackend::up) fail with this message
+	pub(crate) fail_up: Option<&'static str>,
+	/// Makes [down](VpnBackend::down) fail with this message, leaving the tunnel up
+	pub(crate) fail_down: Option<&'static str>,
+	/// Ids of the tunnels that are up
+	pub(crate) running: Vec<u32>,
+	/// What [offered_dns](VpnBackend::offered_dns) returns
+	pub(crate) dns: Vec<Ipv4Addr>,
+	next_id: u32,
+}
+
+#[cfg(test)]
+impl VpnBackend for Scripted {
+	fn prepare(
+		&mut self,
+		server: &LogicalServer,
+		_settings: &TunnelSettings,
+		_user: &UserConfig,
+	) -> Result<()> {
+		self.events.push(format!("prepare {}", server.name));
+		Ok(())
+	}
+
+	fn up(&mut self, server: &LogicalServer, settings: &TunnelSettings) -> Result<ConnectionInfo> {
+		self.events.push(format!("up {}", server.name));
+		if let Some(message) = self.fail_up {
+			anyhow::bail!(message);
+		}
+		self.next_id += 1;
+		self.running.push(self.next_id);
+		Ok(ConnectionInfo::new(
+			server,
+			settings.protocol,
+			settings.interface(),
+			Handle::Scripted {
+				scripted: self.next_id,
+			},
+		))
+	}
+
+	fn down(&mut self, info: &ConnectionInfo) -> Result<()> {
+		let id = match info.handle {
+			Handle::Scripted { scripted } => scripted,
+			ref other => anyhow::bail!("{:?} isn't a scripted tunnel", other),
+		};
+		self.events.push(format!("down {}", info.server_name));
+		if let Some(message) = self.fail_down {
+			anyhow::bail!(message);
+		}
+		self.running.retain(|running| *running != id);
+		Ok(())
+	}
+
+	fn status(&mut self, info: &ConnectionInfo) -> Option<TunnelStatus> {
+		match info.handle {
+			Handle::Scripted { scripted } if self.running.contains(&scripted) => {
+				Some(TunnelStatus {
+					state: Some(State {
+						time: 0,
+						name: "CONNECTED".into(),
+						description: "SUCCESS".into(),
+						local_ip: Some(Ipv4Addr::new(10, 8, 0, 2)),
+						remote_ip: None,
+					}),
+					traffic: Some((4096, 1024)),
+				})
+			}
+			_ => None,
+		}
+	}
+
+	fn offered_dns(&mut self, _info: &ConnectionInfo) -> Result<Vec<Ipv4Addr>> {
+		Ok(self.dns.clone())
+	}
+}
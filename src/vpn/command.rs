@@ -0,0 +1,90 @@
+use std::{
+	io::Write,
+	process::{Command, Output, Stdio},
+};
+
+use anyhow::{bail, Context, Result};
+
+/// Runs external programs. Code that shells out takes one of these, so tests can stand in for the real program.
+pub(crate) trait CommandRunner {
+	/// Runs `program` with `args` and returns its stdout. Fails if it exits unsuccessfully.
+	fn run(&mut self, program: &str, args: &[&str]) -> Result<String>;
+	/// Like [run](Self::run), but writes `input` to the program's stdin. Secrets are passed this way, so they don't show up in the process list.
+	fn run_with_input(&mut self, program: &str, args: &[&str], input: &str) -> Result<String>;
+}
+
+/// Actually runs the programs
+#[derive(Debug, Default)]
+pub(crate) struct SystemRunner;
+
+impl CommandRunner for SystemRunner {
+	fn run(&mut self, program: &str, args: &[&str]) -> Result<String> {
+		let output = Command::new(program)
+			.args(args)
+			.output()
+			.with_context(|| format!("Couldn't run {}", program))?;
+		check_output(program, args, output)
+	}
+
+	fn run_with_input(&mut self, program: &str, args: &[&str], input: &str) -> Result<String> {
+		let mut child = Command::new(program)
+			.args(args)
+			.stdin(Stdio::piped())
+			.stdout(Stdio::piped())
+			.stderr(Stdio::piped())
+			.spawn()
+			.with_context(|| format!("Couldn't run {}", program))?;
+		// Dropping stdin closes it, so the program sees the end of its input
+		child
+			.stdin
+			.take()
+			.context("stdin wasn't piped")?
+			.write_all(input.as_bytes())
+			.with_context(|| format!("Couldn't write to {}", program))?;
+		check_output(program, args, child.wait_with_output()?)
+	}
+}
+
+/// The program's stdout, or its stderr as an error if it failed
+fn check_output(program: &str, args: &[&str], output: Output) -> Result<String> {
+	if !output.status.success() {
+		bail!(
+			"`{} {}` failed: {}",
+			program,
+			args.join(" "),
+			String::from_utf8_lossy(&output.stderr).trim()
+		);
+	}
+	Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+}
+
+/// Records every command instead of running it. Commands whose program or whole command line is in `failing` fail, the others print `stdout`.
+#[cfg(test)]
+#[derive(Debug, Default)]
+pub(crate) struct FakeRunner {
+	pub(crate) commands: Vec<String>,
+	/// What was written to stdin, by [run_with_input](CommandRunner::run_with_input)
+	pub(crate) inputs: Vec<String>,
+	pub(crate) failing: Vec<&'static str>,
+	pub(crate) stdout: String,
+}
+
+#[cfg(test)]
+impl CommandRunner for FakeRunner {
+	fn run(&mut self, program: &str, args: &[&str]) -> Result<String> {
+		let mut command = vec![program];
+		command.extend_from_slice(args);
+		let command = command.join(" ");
+		let fails = self.failing.contains(&program) || self.failing.contains(&command.as_str());
+		self.commands.push(command);
+		if fails {
+			bail!("{} failed", program);
+		}
+		Ok(self.stdout.clone())
+	}
+
+	fn run_with_input(&mut self, program: &str, args: &[&str], input: &str) -> Result<String> {
+		self.inputs.push(input.into());
+		self.run(program, args)
+	}
+}
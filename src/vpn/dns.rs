@@ -0,0 +1,450 @@
+use std::{
+	fs::{read, rename, File, Permissions},
+	io::{BufRead, BufReader, Write},
+	net::Ipv4Addr,
+	os::unix::fs::PermissionsExt,
+	path::{Path, PathBuf},
+};
+
+use anyhow::{bail, Context, Result};
+use chrono::Local;
+use sha2::{Digest, Sha256};
+use tempfile::NamedTempFile;
+
+use super::{
+	command::{CommandRunner, SystemRunner},
+	util::{MetaData, UserConfig},
+};
+use crate::constants::{APP_NAME, RESOLV_CONF};
+
+/// A resolv.conf that can be swapped out for the vpn's dns servers and swapped back afterwards.
+///
+/// The original is moved, not copied, to a backup next to it. That keeps a symlinked resolv.conf a symlink once it is restored.
+#[derive(Debug)]
+pub(crate) struct ResolvConf {
+	path: PathBuf,
+	backup: PathBuf,
+}
+
+impl Default for ResolvConf {
+	/// The system's [RESOLV_CONF]
+	fn default() -> Self {
+		Self::new(RESOLV_CONF)
+	}
+}
+
+impl ResolvConf {
+	pub(crate) fn new<P>(path: P) -> Self
+	where
+		P: Into<PathBuf>,
+	{
+		let path = path.into();
+		let mut backup = path.clone().into_os_string();
+		backup.push(format!(".{}.bak", APP_NAME));
+		Self {
+			path,
+			backup: backup.into(),
+		}
+	}
+
+	/// Backs up the current file and replaces it with one using only `servers`. Returns the hash of the new file, for [ResolvConf::restore].
+	///
+	/// A backup left behind by a session that never restored it, like after a crash, still holds the original, so it is kept and only the generated file is replaced. If the file isn't one of ours either, this fails rather than guess which one is the original.
+	pub(crate) fn apply(&self, servers: &[Ipv4Addr]) -> Result<String> {
+		let mut contents = format!(
+			"{} for dns leak protection. The original is restored on disconnect.\n",
+			self.header()
+		);
+		for server in servers {
+			contents.push_str(&format!("nameserver {}\n", server));
+		}
+
+		let dir = self
+			.path
+			.parent()
+			.context("resolv.conf has no parent dir")?;
+		let mut replacement = NamedTempFile::new_in(dir)?;
+		replacement.write_all(contents.as_bytes())?;
+		// Tempfiles are only readable by their owner, but every program needs to read resolv.conf
+		replacement
+			.as_file()
+			.set_permissions(Permissions::from_mode(0o644))?;
+		if !self.backup.exists() {
+			rename(&self.path, &self.backup)
+				.with_context(|| format!("Couldn't back up {}", self.path.display()))?;
+		} else if !self.generated() {
+			bail!(
+				"{} is left over from an earlier session, but {} was changed since. Move whichever is your real resolv.conf to {} and delete the other, then connect again",
+				self.backup.display(),
+				self.path.display(),
+				self.path.display()
+			);
+		}
+		replacement
+			.persist(&self.path)
+			.with_context(|| format!("Couldn't replace {}", self.path.display()))?;
+		Ok(hash(contents.as_bytes()))
+	}
+
+	/// The first line of the files [ResolvConf::apply] writes
+	fn header(&self) -> String {
+		format!("# Generated by {}", APP_NAME)
+	}
+
+	/// Whether the file is one [ResolvConf::apply] wrote
+	fn generated(&self) -> bool {
+		read(&self.path).is_ok_and(|contents| contents.starts_with(self.header().as_bytes()))
+	}
+
+	/// Whether systemd-resolved generated the file. Then dns has to be changed through resolved instead.
+	fn managed_by_resolved(&self) -> bool {
+		read(&self.path)
+			.map(|contents| String::from_utf8_lossy(&contents).contains("systemd-resolved"))
+			.unwrap_or(false)
+	}
+
+	/// Puts the backup back, but only if the file still hashes to `expected`. Otherwise another program rewrote it while connected, and its version is kept. The original is then moved next to it, under a name with the current time, and that path is returned.
+	pub(crate) fn restore(&self, expected: &str) -> Result<Option<PathBuf>> {
+		if !self.backup.exists() {
+			return Ok(None);
+		}
+		let current = read(&self.path).map(|c| hash(&c)).ok();
+		if current.as_deref() == Some(expected) {
+			rename(&self.backup, &self.path)
+				.with_context(|| format!("Couldn't restore {}", self.path.display()))?;
+			Ok(None)
+		} else {
+			// A later connection would overwrite the backup, so the original gets a name of its own
+			let mut kept = self.path.clone().into_os_string();
+			kept.push(format!(
+				".{}.{}",
+				APP_NAME,
+				Local::now().format("%Y%m%d%H%M%S")
+			));
+			let kept = PathBuf::from(kept);
+			rename(&self.backup, &kept)
+				.with_context(|| format!("Couldn't move {}", self.backup.display()))?;
+			Ok(Some(kept))
+		}
+	}
+}
+
+/// Sets and reverts the system's dns servers. With systemd-resolved, the tun device gets its own dns servers through `resolvectl`. Otherwise resolv.conf is replaced.
+pub(crate) struct Dns<R> {
+	resolv_conf: ResolvConf,
+	runner: R,
+}
+
+impl Default for Dns<SystemRunner> {
+	fn default() -> Self {
+		Self::new(ResolvConf::default(), SystemRunner)
+	}
+}
+
+/// How dns was changed on connect, so it can be undone on disconnect
+#[derive(Debug, PartialEq)]
+pub(crate) enum DnsChange {
+	/// resolv.conf was replaced by a file with this hash
+	ResolvConf(String),
+	/// This link got its own dns servers through systemd-resolved
+	Resolved(String),
+}
+
+impl DnsChange {
+	/// Stores the change in `metadata`, where [Dns::restore] looks for it
+	pub(crate) fn record(self, metadata: &mut MetaData) {
+		match self {
+			Self::ResolvConf(hash) => metadata.resolvconf_hash = Some(hash),
+			Self::Resolved(link) => metadata.resolved_link = Some(link),
+		}
+	}
+}
+
+impl<R> Dns<R>
+where
+	R: CommandRunner,
+{
+	pub(crate) fn new(resolv_conf: ResolvConf, runner: R) -> Self {
+		Self {
+			resolv_conf,
+			runner,
+		}
+	}
+
+	/// Points dns at [custom_dns](UserConfig) or, if there is none, the dns servers the vpn server offers, if dns leak protection is on. `offered` is only called when those are needed. Returns the first server used, and the change to [record](DnsChange::record).
+	pub(crate) fn protect<F>(
+		&mut self,
+		user: &UserConfig,
+		offered: F,
+		interface: &str,
+	) -> Result<Option<(Ipv4Addr, DnsChange)>>
+	where
+		F: FnOnce() -> Result<Vec<Ipv4Addr>>,
+	{
+		if !user.dns_leak_protection {
+			return Ok(None);
+		}
+		let servers = if user.custom_dns.is_empty() {
+			offered()?
+		} else {
+			user.custom_dns.clone()
+		};
+		let first = match servers.first() {
+			Some(first) => *first,
+			None => {
+				bail!("The server didn't push a dns server, so dns leak protection can't be set up")
+			}
+		};
+
+		let change = if self.resolv_conf.managed_by_resolved() {
+			let servers: Vec<_> = servers.iter().map(Ipv4Addr::to_string).collect();
+			let mut args = vec!["dns", interface];
+			args.extend(servers.iter().map(String::as_str));
+			self.runner.run("resolvectl", &args)?;
+			// Routing every domain to the tunnel keeps queries off the other links
+			self.runner
+				.run("resolvectl", &["domain", interface, "~."])?;
+			DnsChange::Resolved(interface.into())
+		} else {
+			DnsChange::ResolvConf(self.resolv_conf.apply(&servers)?)
+		};
+		Ok(Some((first, change)))
+	}
+
+	/// Undoes the change recorded in `metadata`, if any, and forgets it. If resolv.conf had been changed by someone else, it is left alone, and the path the original was moved to is returned. See [ResolvConf::restore].
+	pub(crate) fn restore(&mut self, metadata: &mut MetaData) -> Result<Option<PathBuf>> {
+		if let Some(link) = metadata.resolved_link.take() {
+			// resolved drops the settings of a link by itself once the link is gone
+			if let Err(e) = self.runner.run("resolvectl", &["revert", &link]) {
+				if Path::new("/sys/class/net").join(&link).exists() {
+					return Err(e);
+				}
+			}
+		}
+		match metadata.resolvconf_hash.take() {
+			Some(hash) => self.resolv_conf.restore(&hash),
+			None => Ok(None),
+		}
+	}
+}
+
+/// The dns servers openvpn received from the server, read from its log
+pub(crate) fn pushed_dns(log_path: &Path) -> Result<Vec<Ipv4Addr>> {
+	let log = BufReader::new(File::open(log_path)?);
+	let mut servers = Vec::new();
+	for line in log.lines() {
+		let line = line?;
+		if let Some(start) = line.find("PUSH_REPLY,") {
+			servers = line[start..]
+				.trim_end_matches('\'')
+				.split(',')
+				.filter_map(|option| option.strip_prefix("dhcp-option DNS "))
+				.filter_map(|ip| ip.trim().parse().ok())
+				.collect();
+		}
+	}
+	Ok(servers)
+}
+
+fn hash(contents: &[u8]) -> String {
+	format!("{:x}", Sha256::digest(contents))
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::vpn::command::FakeRunner;
+	use std::fs::{read_to_string, write};
+	use tempfile::tempdir;
+
+	#[test]
+	fn test_apply_and_restore() -> Result<()> {
+		let dir = tempdir()?;
+		let path = dir.path().join("resolv.conf");
+		write(&path, "nameserver 192.168.1.1\n")?;
+		let resolv_conf = ResolvConf::new(&path);
+
+		let hash = resolv_conf.apply(&[Ipv4Addr::new(10, 8, 8, 1)])?;
+		let contents = read_to_string(&path)?;
+		assert!(contents.ends_with("\nnameserver 10.8.8.1\n"));
+		assert!(!contents.contains("192.168.1.1"));
+		assert_eq!(hash, super::hash(contents.as_bytes()));
+		assert_eq!(path.metadata()?.permissions().mode() & 0o777, 0o644);
+
+		assert_eq!(resolv_conf.restore(&hash)?, None);
+		assert_eq!(read_to_string(&path)?, "nameserver 192.168.1.1\n");
+		assert!(!resolv_conf.backup.exists());
+		Ok(())
+	}
+
+	/// A session that never restored leaves the backup behind. The next one must not overwrite it with the generated file.
+	#[test]
+	fn test_apply_twice() -> Result<()> {
+		let dir = tempdir()?;
+		let path = dir.path().join("resolv.conf");
+		write(&path, "nameserver 192.168.1.1\n")?;
+		let resolv_conf = ResolvConf::new(&path);
+
+		resolv_conf.apply(&[Ipv4Addr::new(10, 8, 8, 1)])?;
+		let hash = resolv_conf.apply(&[Ipv4Addr::new(10, 9, 9, 1)])?;
+		assert!(read_to_string(&path)?.ends_with("\nnameserver 10.9.9.1\n"));
+		assert_eq!(
+			read_to_string(&resolv_conf.backup)?,
+			"nameserver 192.168.1.1\n"
+		);
+
+		assert_eq!(resolv_conf.restore(&hash)?, None);
+		assert_eq!(read_to_string(&path)?, "nameserver 192.168.1.1\n");
+
+		// A leftover backup next to a file that isn't ours is ambiguous
+		write(&resolv_conf.backup, "nameserver 192.168.1.1\n")?;
+		let err = resolv_conf
+			.apply(&[Ipv4Addr::new(10, 8, 8, 1)])
+			.unwrap_err();
+		assert!(err.to_string().contains("left over"), "{}", err);
+		assert_eq!(read_to_string(&path)?, "nameserver 192.168.1.1\n");
+		assert_eq!(
+			read_to_string(&resolv_conf.backup)?,
+			"nameserver 192.168.1.1\n"
+		);
+		Ok(())
+	}
+
+	#[test]
+	fn test_restore_changed_file() -> Result<()> {
+		let dir = tempdir()?;
+		let path = dir.path().join("resolv.conf");
+		write(&path, "nameserver 192.168.1.1\n")?;
+		let resolv_conf = ResolvConf::new(&path);
+
+		let hash = resolv_conf.apply(&[Ipv4Addr::new(10, 8, 8, 1)])?;
+		write(&path, "nameserver 1.1.1.1\n")?;
+		let kept = resolv_conf.restore(&hash)?.unwrap();
+		assert_eq!(read_to_string(&path)?, "nameserver 1.1.1.1\n");
+		assert!(!resolv_conf.backup.exists());
+		// The original is never thrown away
+		assert_eq!(read_to_string(&kept)?, "nameserver 192.168.1.1\n");
+		assert!(kept
+			.file_name()
+			.unwrap()
+			.to_string_lossy()
+			.starts_with("resolv.conf.protonvpn-rs."));
+		Ok(())
+	}
+
+	fn dns_in(dir: &Path, resolv_conf: &str) -> Result<Dns<FakeRunner>> {
+		let path = dir.join("resolv.conf");
+		write(&path, resolv_conf)?;
+		Ok(Dns::new(ResolvConf::new(&path), FakeRunner::default()))
+	}
+
+	fn user_with_dns() -> UserConfig {
+		UserConfig {
+			custom_dns: vec![Ipv4Addr::new(10, 8, 8, 1), Ipv4Addr::new(10, 8, 8, 2)],
+			..Default::default()
+		}
+	}
+
+	#[test]
+	fn test_protect_resolv_conf() -> Result<()> {
+		let dir = tempdir()?;
+		let mut dns = dns_in(dir.path(), "nameserver 192.168.1.1\n")?;
+		let mut metadata = MetaData::default();
+
+		// Nothing to restore
+		assert_eq!(dns.restore(&mut metadata)?, None);
+
+		let (first, change) = dns
+			.protect(
+				&user_with_dns(),
+				|| pushed_dns(Path::new("no log")),
+				"proton0",
+			)?
+			.unwrap();
+		assert_eq!(first, Ipv4Addr::new(10, 8, 8, 1));
+		assert!(matches!(change, DnsChange::ResolvConf(_)));
+		change.record(&mut metadata);
+		assert!(metadata.resolvconf_hash.is_some());
+
+		assert_eq!(dns.restore(&mut metadata)?, None);
+		assert_eq!(metadata.resolvconf_hash, None);
+		assert_eq!(
+			read_to_string(dir.path().join("resolv.conf"))?,
+			"nameserver 192.168.1.1\n"
+		);
+		assert!(dns.runner.commands.is_empty());
+		Ok(())
+	}
+
+	#[test]
+	fn test_protect_resolved() -> Result<()> {
+		let stub = "# This file is managed by man:systemd-resolved(8). Do not edit.\nnameserver 127.0.0.53\n";
+		let dir = tempdir()?;
+		let mut dns = dns_in(dir.path(), stub)?;
+		let mut metadata = MetaData::default();
+
+		let (_, change) = dns
+			.protect(
+				&user_with_dns(),
+				|| pushed_dns(Path::new("no log")),
+				"proton0",
+			)?
+			.unwrap();
+		assert_eq!(change, DnsChange::Resolved("proton0".into()));
+		change.record(&mut metadata);
+		assert_eq!(read_to_string(dir.path().join("resolv.conf"))?, stub);
+
+		assert_eq!(dns.restore(&mut metadata)?, None);
+		assert_eq!(metadata.resolved_link, None);
+		assert_eq!(
+			dns.runner.commands,
+			[
+				"resolvectl dns proton0 10.8.8.1 10.8.8.2",
+				"resolvectl domain proton0 ~.",
+				"resolvectl revert proton0"
+			]
+		);
+
+		// A failing resolvectl fails the connection
+		dns.runner.failing.push("resolvectl");
+		assert!(dns
+			.protect(
+				&user_with_dns(),
+				|| pushed_dns(Path::new("no log")),
+				"proton0"
+			)
+			.is_err());
+		Ok(())
+	}
+
+	#[test]
+	fn test_protect_disabled() -> Result<()> {
+		let dir = tempdir()?;
+		let mut dns = dns_in(dir.path(), "nameserver 192.168.1.1\n")?;
+		let mut user = user_with_dns();
+		user.dns_leak_protection = false;
+		assert_eq!(
+			dns.protect(&user, || pushed_dns(Path::new("no log")), "proton0")?,
+			None
+		);
+		Ok(())
+	}
+
+	#[test]
+	fn test_pushed_dns() -> Result<()> {
+		let dir = tempdir()?;
+		let log = dir.path().join("ovpn.log");
+		write(
+			&log,
+			"Mon Jan 4 12:00:00 2021 OpenVPN 2.5.0 x86_64-pc-linux-gnu
+Mon Jan 4 12:00:01 2021 PUSH: Received control message: 'PUSH_REPLY,dhcp-option DNS 10.8.8.1,dhcp-option DNS 10.8.8.2,redirect-gateway def1,route-gateway 10.8.8.1,ifconfig 10.8.8.7 255.255.255.0,peer-id 3'
+Mon Jan 4 12:00:01 2021 Initialization Sequence Completed
+",
+		)?;
+		assert_eq!(
+			pushed_dns(&log)?,
+			[Ipv4Addr::new(10, 8, 8, 1), Ipv4Addr::new(10, 8, 8, 2)]
+		);
+		Ok(())
+	}
+}
@@ -0,0 +1,248 @@
+use std::{
+	fmt::{self, Display},
+	net::Ipv4Addr,
+};
+
+use anyhow::{bail, Result};
+
+use super::{
+	command::{CommandRunner, SystemRunner},
+	split_tunnel::Cidr,
+	util::ConnectionProtocol,
+};
+use crate::{constants::TUN_DEVICE, utils::LogicalServer};
+
+mod iptables;
+mod nftables;
+
+pub(crate) use iptables::Iptables;
+pub(crate) use nftables::Nftables;
+
+/// A way of installing the kill switch. Implementations must make [FirewallBackend::apply] replace earlier rules rather than add to them, and [FirewallBackend::flush] must succeed when nothing is installed.
+pub(crate) trait FirewallBackend {
+	/// Drops all traffic except what `rules` allows, replacing any rules from an earlier session
+	fn apply(&mut self, rules: &KillSwitchRules) -> Result<()>;
+	/// Removes every kill switch rule this crate installed
+	fn flush(&mut self) -> Result<()>;
+	/// Drops all outgoing ipv6 traffic except loopback, for tunnels that only carry ipv4. Independent of the kill switch.
+	fn block_ipv6(&mut self) -> Result<()>;
+	/// Undoes [FirewallBackend::block_ipv6]. Must succeed when ipv6 isn't blocked.
+	fn unblock_ipv6(&mut self) -> Result<()>;
+}
+
+/// Private and link-local ipv4 networks, reachable when the kill switch allows the local network
+const LAN_IPV4: [&str; 4] = [
+	"10.0.0.0/8",
+	"172.16.0.0/12",
+	"192.168.0.0/16",
+	"169.254.0.0/16",
+];
+/// Link-local and unique local ipv6 networks
+const LAN_IPV6: [&str; 2] = ["fe80::/10", "fc00::/7"];
+
+/// What the kill switch lets through: loopback, the tunnel's interface, dhcp, the vpn traffic to a server's entry ips, the networks split tunneling routes around the vpn and optionally the local network. Everything else is dropped.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct KillSwitchRules {
+	interface: &'static str,
+	entry_ips: Vec<Ipv4Addr>,
+	protocol: ConnectionProtocol,
+	ports: Vec<u16>,
+	lan: bool,
+	bypass: Vec<Cidr>,
+}
+
+impl KillSwitchRules {
+	pub(crate) fn new(server: &LogicalServer, protocol: ConnectionProtocol, ports: &[u16]) -> Self {
+		Self {
+			interface: TUN_DEVICE,
+			entry_ips: server.servers.iter().map(|s| s.entry_ip).collect(),
+			protocol,
+			ports: ports.to_vec(),
+			lan: false,
+			bypass: vec![],
+		}
+	}
+
+	/// Allows the tunnel on `interface` instead of [TUN_DEVICE]
+	pub(crate) fn on_interface(mut self, interface: &'static str) -> Self {
+		self.interface = interface;
+		self
+	}
+
+	/// Also lets through traffic to and from the private networks, like printers and file shares
+	pub(crate) fn allow_lan(mut self, allow: bool) -> Self {
+		self.lan = allow;
+		self
+	}
+
+	/// Also lets through traffic to `networks`, which split tunneling routes outside the tunnel
+	pub(crate) fn bypass(mut self, networks: Vec<Cidr>) -> Self {
+		self.bypass = networks;
+		self
+	}
+
+	/// The local networks of one address family, or none if they aren't allowed
+	fn lan_networks(&self, ipv6: bool) -> &'static [&'static str] {
+		match (self.lan, ipv6) {
+			(false, _) => &[],
+			(true, false) => &LAN_IPV4,
+			(true, true) => &LAN_IPV6,
+		}
+	}
+}
+
+impl Display for KillSwitchRules {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		let join = |items: Vec<String>| items.join(", ");
+		write!(
+			f,
+			"allow loopback, {}, dhcp{}{} and {} to {} on port {}, drop everything else",
+			self.interface,
+			if self.lan { ", the local network" } else { "" },
+			if self.bypass.is_empty() {
+				String::new()
+			} else {
+				format!(
+					", {} outside the vpn",
+					join(self.bypass.iter().map(Cidr::to_string).collect())
+				)
+			},
+			self.protocol,
+			join(self.entry_ips.iter().map(Ipv4Addr::to_string).collect()),
+			join(self.ports.iter().map(u16::to_string).collect()),
+		)
+	}
+}
+
+/// Uses nftables if `nft` is installed, and iptables otherwise. Detection happens on first use, so nothing is run unless the kill switch is needed.
+#[derive(Default)]
+pub(crate) struct AutoDetect {
+	backend: Option<Box<dyn FirewallBackend>>,
+}
+
+impl AutoDetect {
+	fn backend(&mut self) -> Result<&mut dyn FirewallBackend> {
+		if self.backend.is_none() {
+			self.backend = Some(detect()?);
+		}
+		Ok(self.backend.as_deref_mut().unwrap())
+	}
+}
+
+impl FirewallBackend for AutoDetect {
+	fn apply(&mut self, rules: &KillSwitchRules) -> Result<()> {
+		self.backend()?.apply(rules)
+	}
+
+	fn flush(&mut self) -> Result<()> {
+		self.backend()?.flush()
+	}
+
+	fn block_ipv6(&mut self) -> Result<()> {
+		self.backend()?.block_ipv6()
+	}
+
+	fn unblock_ipv6(&mut self) -> Result<()> {
+		self.backend()?.unblock_ipv6()
+	}
+}
+
+fn detect() -> Result<Box<dyn FirewallBackend>> {
+	let mut runner = SystemRunner;
+	let mut is_installed = |program| runner.run(program, &["--version"]).is_ok();
+	if is_installed("nft") {
+		Ok(Box::new(Nftables::new(SystemRunner)))
+	} else if is_installed("iptables-restore") {
+		Ok(Box::new(Iptables::new(SystemRunner)))
+	} else {
+		bail!("The kill switch needs nftables or iptables, but neither is installed")
+	}
+}
+
+/// Doesn't touch the firewall, just remembers what would have been done. For tests and `--dry-run`.
+#[derive(Debug, Default)]
+pub(crate) struct Recording {
+	pub(crate) changes: Vec<String>,
+}
+
+impl FirewallBackend for Recording {
+	fn apply(&mut self, rules: &KillSwitchRules) -> Result<()> {
+		self.changes.push(format!("enable kill switch: {}", rules));
+		Ok(())
+	}
+
+	fn flush(&mut self) -> Result<()> {
+		self.changes.push("disable kill switch".into());
+		Ok(())
+	}
+
+	fn block_ipv6(&mut self) -> Result<()> {
+		self.changes.push("block outgoing ipv6".into());
+		Ok(())
+	}
+
+	fn unblock_ipv6(&mut self) -> Result<()> {
+		self.changes.push("unblock outgoing ipv6".into());
+		Ok(())
+	}
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+	use super::*;
+	use crate::utils::Server;
+
+	/// A server with two entry ips
+	pub(crate) fn two_entry_server() -> LogicalServer {
+		let mut server = LogicalServer::mock("CH#1", 2, 1.0, 10);
+		server.servers = (1..=2)
+			.map(|i| Server {
+				entry_ip: Ipv4Addr::new(185, 159, 157, i),
+				exit_ip: Ipv4Addr::new(185, 159, 158, i),
+				domain: format!("node-ch-0{}.protonvpn.net", i),
+				id: i.to_string(),
+				status: 1,
+				x25519_public_key: None,
+			})
+			.collect();
+		server
+	}
+
+	#[test]
+	fn test_display() {
+		let rules = KillSwitchRules::new(&two_entry_server(), ConnectionProtocol::UDP, &[1194])
+			.allow_lan(true);
+		assert_eq!(
+			rules.to_string(),
+			"allow loopback, proton0, dhcp, the local network and UDP to 185.159.157.1, 185.159.157.2 on port 1194, drop everything else"
+		);
+		assert_eq!(rules.lan_networks(true), LAN_IPV6);
+
+		let rules = rules.bypass(vec![
+			"10.1.0.0/16".parse().unwrap(),
+			"1.1.1.1".parse().unwrap(),
+		]);
+		assert!(rules.to_string().starts_with("allow loopback, proton0, dhcp, the local network, 10.1.0.0/16, 1.1.1.1/32 outside the vpn and UDP to"));
+		assert!(rules.allow_lan(false).lan_networks(false).is_empty());
+	}
+
+	#[test]
+	fn test_recording() -> Result<()> {
+		let rules = KillSwitchRules::new(&two_entry_server(), ConnectionProtocol::TCP, &[443]);
+		let mut firewall = Recording::default();
+		firewall.apply(&rules)?;
+		firewall.block_ipv6()?;
+		firewall.flush()?;
+		firewall.unblock_ipv6()?;
+		assert_eq!(
+			firewall.changes,
+			[
+				"enable kill switch: allow loopback, proton0, dhcp and TCP to 185.159.157.1, 185.159.157.2 on port 443, drop everything else",
+				"block outgoing ipv6",
+				"disable kill switch",
+				"unblock outgoing ipv6"
+			]
+		);
+		Ok(())
+	}
+}
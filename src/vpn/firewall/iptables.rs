@@ -0,0 +1,262 @@
+use anyhow::{Context, Result};
+use askama::Template;
+
+use super::{FirewallBackend, KillSwitchRules};
+use crate::vpn::command::CommandRunner;
+
+/// Chain for the kill switch's rules on the `OUTPUT` hook
+const OUTPUT_CHAIN: &str = "PROTONVPN-OUTPUT";
+/// Chain for the kill switch's rules on the `INPUT` hook
+const INPUT_CHAIN: &str = "PROTONVPN-INPUT";
+/// Ip6tables chain on the `OUTPUT` hook that blocks ipv6 for ipv4 only tunnels
+const IPV6_CHAIN: &str = "PROTONVPN-IPV6";
+
+/// For systems without nftables. The rules live in their own chains, which `iptables-restore --noflush` refills in one step, so reapplying never opens a gap. Ipv6 gets the same rules through the ip6tables tools, minus the ipv4 server addresses.
+pub(crate) struct Iptables<R> {
+	runner: R,
+}
+
+impl<R> Iptables<R> {
+	pub(crate) fn new(runner: R) -> Self {
+		Self { runner }
+	}
+}
+
+#[derive(Template)]
+#[template(path = "killswitch.iptables.j2", escape = "none")]
+struct Ruleset<'a> {
+	output_chain: &'static str,
+	input_chain: &'static str,
+	rules: &'a KillSwitchRules,
+	ipv6: bool,
+	/// [KillSwitchRules::lan_networks] of this family
+	lan: &'static [&'static str],
+}
+
+/// The ipv4 and ipv6 variants of the iptables tools
+const FAMILIES: [(&str, &str, bool); 2] = [
+	("iptables", "iptables-restore", false),
+	("ip6tables", "ip6tables-restore", true),
+];
+
+/// The hooks the kill switch's chains are jumped to from
+const HOOKS: [(&str, &str); 2] = [("OUTPUT", OUTPUT_CHAIN), ("INPUT", INPUT_CHAIN)];
+
+impl<R: CommandRunner> FirewallBackend for Iptables<R> {
+	fn apply(&mut self, rules: &KillSwitchRules) -> Result<()> {
+		for (iptables, restore, ipv6) in FAMILIES {
+			let ruleset = Ruleset {
+				output_chain: OUTPUT_CHAIN,
+				input_chain: INPUT_CHAIN,
+				rules,
+				ipv6,
+				lan: rules.lan_networks(ipv6),
+			}
+			.render()
+			.context("Rendering kill switch rules failed")?;
+			// iptables-restore wants the COMMIT line terminated
+			self.runner
+				.run_with_input(restore, &["--noflush"], &(ruleset + "\n"))
+				.context("Couldn't enable the kill switch")?;
+			for (hook, chain) in HOOKS {
+				self.jump_to(iptables, hook, chain)?;
+			}
+		}
+		Ok(())
+	}
+
+	fn flush(&mut self) -> Result<()> {
+		for (iptables, _, _) in FAMILIES {
+			for (hook, chain) in HOOKS {
+				self.remove_chain(iptables, hook, chain)?;
+			}
+		}
+		Ok(())
+	}
+
+	fn block_ipv6(&mut self) -> Result<()> {
+		self.runner
+			.run_with_input("ip6tables-restore", &["--noflush"], &block_ipv6_ruleset())
+			.context("Couldn't block ipv6")?;
+		self.jump_to("ip6tables", "OUTPUT", IPV6_CHAIN)
+	}
+
+	fn unblock_ipv6(&mut self) -> Result<()> {
+		self.remove_chain("ip6tables", "OUTPUT", IPV6_CHAIN)
+	}
+}
+
+impl<R: CommandRunner> Iptables<R> {
+	/// Makes `hook` jump to `chain` first, unless it already does
+	fn jump_to(&mut self, iptables: &str, hook: &str, chain: &str) -> Result<()> {
+		if self.succeeds(iptables, &["-C", hook, "-j", chain]) {
+			return Ok(());
+		}
+		self.runner.run(iptables, &["-I", hook, "1", "-j", chain])?;
+		Ok(())
+	}
+
+	/// Removes every jump from `hook` to `chain`, then `chain` itself. Fine if neither exists.
+	fn remove_chain(&mut self, iptables: &str, hook: &str, chain: &str) -> Result<()> {
+		while self.succeeds(iptables, &["-C", hook, "-j", chain]) {
+			self.runner.run(iptables, &["-D", hook, "-j", chain])?;
+		}
+		if self.succeeds(iptables, &["-n", "-L", chain]) {
+			self.runner.run(iptables, &["-F", chain])?;
+			self.runner.run(iptables, &["-X", chain])?;
+		}
+		Ok(())
+	}
+
+	fn succeeds(&mut self, iptables: &str, args: &[&str]) -> bool {
+		self.runner.run(iptables, args).is_ok()
+	}
+}
+
+fn block_ipv6_ruleset() -> String {
+	format!(
+		"*filter\n:{0} - [0:0]\n-A {0} -o lo -j ACCEPT\n-A {0} -j DROP\nCOMMIT\n",
+		IPV6_CHAIN
+	)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::vpn::{
+		command::FakeRunner, firewall::tests::two_entry_server, util::ConnectionProtocol,
+	};
+
+	#[test]
+	fn test_iptables() -> Result<()> {
+		let rules = KillSwitchRules::new(&two_entry_server(), ConnectionProtocol::UDP, &[1194]);
+		let mut runner = FakeRunner::default();
+		// No jumps exist yet
+		for check in [
+			"iptables -C OUTPUT -j PROTONVPN-OUTPUT",
+			"iptables -C INPUT -j PROTONVPN-INPUT",
+			"ip6tables -C OUTPUT -j PROTONVPN-OUTPUT",
+			"ip6tables -C INPUT -j PROTONVPN-INPUT",
+		] {
+			runner.failing.push(check);
+		}
+		let mut iptables = Iptables::new(runner);
+		iptables.apply(&rules)?;
+		assert_eq!(
+			iptables.runner.commands,
+			[
+				"iptables-restore --noflush",
+				"iptables -C OUTPUT -j PROTONVPN-OUTPUT",
+				"iptables -I OUTPUT 1 -j PROTONVPN-OUTPUT",
+				"iptables -C INPUT -j PROTONVPN-INPUT",
+				"iptables -I INPUT 1 -j PROTONVPN-INPUT",
+				"ip6tables-restore --noflush",
+				"ip6tables -C OUTPUT -j PROTONVPN-OUTPUT",
+				"ip6tables -I OUTPUT 1 -j PROTONVPN-OUTPUT",
+				"ip6tables -C INPUT -j PROTONVPN-INPUT",
+				"ip6tables -I INPUT 1 -j PROTONVPN-INPUT",
+			]
+		);
+		assert!(iptables.runner.inputs[0].contains("-d 185.159.157.1 "));
+		assert!(!iptables.runner.inputs[1].contains("185.159.157.1"));
+		assert!(iptables.runner.inputs[1].ends_with("COMMIT\n"));
+
+		// The chains exist, but nothing jumps to them anymore
+		iptables.runner.commands.clear();
+		iptables.flush()?;
+		assert_eq!(
+			iptables.runner.commands[..4],
+			[
+				"iptables -C OUTPUT -j PROTONVPN-OUTPUT",
+				"iptables -n -L PROTONVPN-OUTPUT",
+				"iptables -F PROTONVPN-OUTPUT",
+				"iptables -X PROTONVPN-OUTPUT",
+			]
+		);
+		assert_eq!(iptables.runner.commands.len(), 16);
+		Ok(())
+	}
+
+	#[test]
+	fn test_ruleset() {
+		let rules = KillSwitchRules::new(&two_entry_server(), ConnectionProtocol::UDP, &[80, 1194]);
+		let render = |ipv6| {
+			Ruleset {
+				output_chain: OUTPUT_CHAIN,
+				input_chain: INPUT_CHAIN,
+				rules: &rules,
+				ipv6,
+				lan: rules.lan_networks(ipv6),
+			}
+			.render()
+			.unwrap()
+		};
+
+		let expected = "*filter
+:PROTONVPN-OUTPUT - [0:0]
+:PROTONVPN-INPUT - [0:0]
+-A PROTONVPN-OUTPUT -o lo -j ACCEPT
+-A PROTONVPN-OUTPUT -o proton0 -j ACCEPT
+-A PROTONVPN-OUTPUT -p udp --sport 68 --dport 67 -j ACCEPT
+-A PROTONVPN-OUTPUT -d 185.159.157.1 -p udp -m multiport --dports 80,1194 -j ACCEPT
+-A PROTONVPN-OUTPUT -d 185.159.157.2 -p udp -m multiport --dports 80,1194 -j ACCEPT
+-A PROTONVPN-OUTPUT -j DROP
+-A PROTONVPN-INPUT -i lo -j ACCEPT
+-A PROTONVPN-INPUT -i proton0 -j ACCEPT
+-A PROTONVPN-INPUT -p udp --sport 67 --dport 68 -j ACCEPT
+-A PROTONVPN-INPUT -m conntrack --ctstate ESTABLISHED,RELATED -j ACCEPT
+-A PROTONVPN-INPUT -j DROP
+COMMIT";
+		assert_eq!(render(false), expected);
+		assert!(!render(true).contains("185.159.157.1"));
+		assert!(render(true).contains("-A PROTONVPN-OUTPUT -j DROP"));
+		assert!(!render(true).contains("--dport 67"));
+
+		let rules = rules.allow_lan(true);
+		let render = |ipv6| {
+			Ruleset {
+				output_chain: OUTPUT_CHAIN,
+				input_chain: INPUT_CHAIN,
+				rules: &rules,
+				ipv6,
+				lan: rules.lan_networks(ipv6),
+			}
+			.render()
+			.unwrap()
+		};
+		assert!(render(false).contains("-A PROTONVPN-OUTPUT -d 192.168.0.0/16 -j ACCEPT\n"));
+		assert!(render(false).contains("-A PROTONVPN-INPUT -s 192.168.0.0/16 -j ACCEPT\n"));
+		assert!(render(true).contains("-A PROTONVPN-OUTPUT -d fe80::/10 -j ACCEPT\n"));
+		assert!(!render(true).contains("192.168.0.0/16"));
+
+		let rules = rules.bypass(vec!["10.1.0.0/16".parse().unwrap()]);
+		let render = |ipv6| {
+			Ruleset {
+				output_chain: OUTPUT_CHAIN,
+				input_chain: INPUT_CHAIN,
+				rules: &rules,
+				ipv6,
+				lan: rules.lan_networks(ipv6),
+			}
+			.render()
+			.unwrap()
+		};
+		assert!(render(false).contains(
+			"-A PROTONVPN-OUTPUT -d 10.1.0.0/16 -j ACCEPT\n-A PROTONVPN-OUTPUT -d 185.159.157.1 "
+		));
+		assert!(!render(true).contains("10.1.0.0/16"));
+	}
+
+	#[test]
+	fn test_block_ipv6_ruleset() {
+		assert_eq!(
+			block_ipv6_ruleset(),
+			"*filter
+:PROTONVPN-IPV6 - [0:0]
+-A PROTONVPN-IPV6 -o lo -j ACCEPT
+-A PROTONVPN-IPV6 -j DROP
+COMMIT
+"
+		);
+	}
+}
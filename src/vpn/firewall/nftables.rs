@@ -0,0 +1,217 @@
+use anyhow::{Context, Result};
+use askama::Template;
+
+use super::{FirewallBackend, KillSwitchRules};
+use crate::vpn::command::CommandRunner;
+
+/// Name of the nftables table holding the kill switch. Deleting it removes the kill switch.
+const TABLE: &str = "protonvpn";
+/// Name of the `ip6` table that blocks ipv6 for ipv4 only tunnels
+const IPV6_TABLE: &str = "protonvpn-ipv6";
+
+/// Everything lives in one `inet` table, so ipv6 is dropped as well. Loading a ruleset replaces the earlier version in one transaction, so there is no window where traffic can leak.
+pub(crate) struct Nftables<R> {
+	runner: R,
+}
+
+impl<R> Nftables<R> {
+	pub(crate) fn new(runner: R) -> Self {
+		Self { runner }
+	}
+}
+
+#[derive(Template)]
+#[template(path = "killswitch.nft.j2", escape = "none")]
+struct Ruleset<'a> {
+	table: &'static str,
+	rules: &'a KillSwitchRules,
+}
+
+impl<R: CommandRunner> Nftables<R> {
+	/// Loads `ruleset` in one transaction
+	fn load(&mut self, ruleset: &str) -> Result<()> {
+		self.runner.run_with_input("nft", &["-f", "-"], ruleset)?;
+		Ok(())
+	}
+}
+
+impl<R: CommandRunner> FirewallBackend for Nftables<R> {
+	fn apply(&mut self, rules: &KillSwitchRules) -> Result<()> {
+		let ruleset = Ruleset {
+			table: TABLE,
+			rules,
+		}
+		.render()
+		.context("Rendering kill switch rules failed")?;
+		self.load(&ruleset)
+			.context("Couldn't enable the kill switch")
+	}
+
+	fn flush(&mut self) -> Result<()> {
+		self.load(&delete_table("inet", TABLE))
+			.context("Couldn't disable the kill switch")
+	}
+
+	fn block_ipv6(&mut self) -> Result<()> {
+		self.load(&block_ipv6_ruleset())
+			.context("Couldn't block ipv6")
+	}
+
+	fn unblock_ipv6(&mut self) -> Result<()> {
+		self.load(&delete_table("ip6", IPV6_TABLE))
+			.context("Couldn't unblock ipv6")
+	}
+}
+
+/// Declaring the table first makes the delete succeed even if the table doesn't exist yet
+fn delete_table(family: &str, table: &str) -> String {
+	format!("table {0} {1}\ndelete table {0} {1}\n", family, table)
+}
+
+fn block_ipv6_ruleset() -> String {
+	format!(
+		"{}table ip6 {} {{\n\tchain output {{\n\t\ttype filter hook output priority 0; policy drop;\n\t\toifname \"lo\" accept\n\t}}\n}}\n",
+		delete_table("ip6", IPV6_TABLE),
+		IPV6_TABLE
+	)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::vpn::{
+		command::FakeRunner, firewall::tests::two_entry_server, util::ConnectionProtocol,
+	};
+
+	#[test]
+	fn test_nftables() -> Result<()> {
+		let rules = KillSwitchRules::new(&two_entry_server(), ConnectionProtocol::UDP, &[1194]);
+		let mut nftables = Nftables::new(FakeRunner::default());
+		nftables.apply(&rules)?;
+		nftables.block_ipv6()?;
+		nftables.unblock_ipv6()?;
+		nftables.flush()?;
+		assert_eq!(nftables.runner.commands, ["nft -f -"; 4]);
+		let inputs = &nftables.runner.inputs;
+		assert!(inputs[0].starts_with("table inet protonvpn\ndelete table inet protonvpn\n"));
+		assert!(inputs[0].contains("ip daddr { 185.159.157.1, 185.159.157.2 }"));
+		assert_eq!(inputs[1], block_ipv6_ruleset());
+		assert_eq!(inputs[2], delete_table("ip6", IPV6_TABLE));
+		assert_eq!(inputs[3], delete_table("inet", TABLE));
+
+		nftables.runner.failing.push("nft");
+		let err = nftables.apply(&rules).unwrap_err();
+		assert_eq!(err.to_string(), "Couldn't enable the kill switch");
+		Ok(())
+	}
+
+	#[test]
+	fn test_ruleset() {
+		let rules = KillSwitchRules::new(&two_entry_server(), ConnectionProtocol::UDP, &[80, 1194]);
+		let ruleset = Ruleset {
+			table: TABLE,
+			rules: &rules,
+		};
+		let expected = r#"table inet protonvpn
+delete table inet protonvpn
+table inet protonvpn {
+	chain output {
+		type filter hook output priority 0; policy drop;
+		oifname "lo" accept
+		oifname "proton0" accept
+		udp sport 68 udp dport 67 accept
+		ip daddr { 185.159.157.1, 185.159.157.2 } udp dport { 80, 1194 } accept
+	}
+
+	chain input {
+		type filter hook input priority 0; policy drop;
+		iifname "lo" accept
+		iifname "proton0" accept
+		udp sport 67 udp dport 68 accept
+		ct state established,related accept
+	}
+}"#;
+		assert_eq!(ruleset.render().unwrap(), expected);
+
+		let rules = KillSwitchRules::new(&two_entry_server(), ConnectionProtocol::TCP, &[443]);
+		let ruleset = Ruleset {
+			table: TABLE,
+			rules: &rules,
+		};
+		assert!(ruleset
+			.render()
+			.unwrap()
+			.contains("ip daddr { 185.159.157.1, 185.159.157.2 } tcp dport { 443 } accept"));
+	}
+
+	#[test]
+	fn test_ruleset_lan() {
+		let rules = KillSwitchRules::new(&two_entry_server(), ConnectionProtocol::UDP, &[1194])
+			.allow_lan(true);
+		let ruleset = Ruleset {
+			table: TABLE,
+			rules: &rules,
+		}
+		.render()
+		.unwrap();
+		assert!(ruleset.contains(
+			"\t\tudp sport 68 udp dport 67 accept\n\t\tip daddr { 10.0.0.0/8, 172.16.0.0/12, 192.168.0.0/16, 169.254.0.0/16 } accept\n\t\tip6 daddr { fe80::/10, fc00::/7 } accept\n"
+		));
+		assert!(ruleset.contains("\t\tip6 saddr { fe80::/10, fc00::/7 } accept\n\t\tct state"));
+	}
+
+	#[test]
+	fn test_ruleset_bypass() {
+		let rules = KillSwitchRules::new(&two_entry_server(), ConnectionProtocol::UDP, &[1194])
+			.bypass(vec![
+				"192.168.5.0/24".parse().unwrap(),
+				"1.1.1.1".parse().unwrap(),
+			]);
+		let ruleset = Ruleset {
+			table: TABLE,
+			rules: &rules,
+		}
+		.render()
+		.unwrap();
+		assert!(ruleset.contains(
+			"\t\tudp sport 68 udp dport 67 accept\n\t\tip daddr { 192.168.5.0/24, 1.1.1.1/32 } accept\n"
+		));
+	}
+
+	#[test]
+	fn test_ruleset_without_entry_ips() {
+		let mut server = two_entry_server();
+		server.servers.clear();
+		let rules = KillSwitchRules::new(&server, ConnectionProtocol::UDP, &[1194]);
+		let ruleset = Ruleset {
+			table: TABLE,
+			rules: &rules,
+		}
+		.render()
+		.unwrap();
+		assert!(!ruleset.contains("daddr"), "{}", ruleset);
+		assert!(ruleset.contains("udp dport 67 accept\n\t}"), "{}", ruleset);
+	}
+
+	#[test]
+	fn test_delete_table() {
+		assert_eq!(
+			delete_table("inet", TABLE),
+			"table inet protonvpn\ndelete table inet protonvpn\n"
+		);
+	}
+
+	#[test]
+	fn test_block_ipv6_ruleset() {
+		let expected = r#"table ip6 protonvpn-ipv6
+delete table ip6 protonvpn-ipv6
+table ip6 protonvpn-ipv6 {
+	chain output {
+		type filter hook output priority 0; policy drop;
+		oifname "lo" accept
+	}
+}
+"#;
+		assert_eq!(block_ipv6_ruleset(), expected);
+	}
+}
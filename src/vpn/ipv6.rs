@@ -0,0 +1,99 @@
+use std::fs::read_to_string;
+
+/// How a session handles ipv6, decided before connecting
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub(crate) struct Ipv6Plan {
+	/// Ignore the ipv6 settings the server pushes. See [TunnelSettings](super::TunnelSettings).
+	pub(crate) ipv6_disabled: bool,
+	/// Block ipv6 outside the tunnel, because the host has it but the tunnel doesn't carry it
+	pub(crate) block_egress: bool,
+}
+
+impl Ipv6Plan {
+	/// `host` is whether the host can reach the ipv6 internet, `server` whether the server supports ipv6
+	pub(crate) fn new(host: bool, server: bool) -> Self {
+		let tunnel = host && server;
+		Self {
+			ipv6_disabled: !tunnel,
+			block_egress: host && !tunnel,
+		}
+	}
+}
+
+/// Whether the host has a global ipv6 address and a default route outside loopback
+pub(crate) fn host_has_ipv6() -> bool {
+	let addresses = read_to_string("/proc/net/if_inet6").unwrap_or_default();
+	let routes = read_to_string("/proc/net/ipv6_route").unwrap_or_default();
+	has_global_address(&addresses) && has_default_route(&routes)
+}
+
+/// Parses `/proc/net/if_inet6`: address, interface index, prefix length, scope, flags and interface name
+fn has_global_address(if_inet6: &str) -> bool {
+	if_inet6.lines().any(|line| {
+		let fields: Vec<_> = line.split_whitespace().collect();
+		matches!(fields.as_slice(), [_, _, _, "00", _, name] if *name != "lo")
+	})
+}
+
+/// Parses `/proc/net/ipv6_route`: destination and its prefix length, source and its prefix length, next hop, metric, refcount, use count, flags and interface name
+fn has_default_route(ipv6_route: &str) -> bool {
+	const ANY: &str = "00000000000000000000000000000000";
+	/// Routes with this flag drop traffic rather than forward it
+	const RTF_REJECT: u32 = 0x0200;
+
+	ipv6_route.lines().any(|line| {
+		let fields: Vec<_> = line.split_whitespace().collect();
+		match fields.as_slice() {
+			[ANY, "00", _, _, _, _, _, _, flags, name] => {
+				let flags = u32::from_str_radix(flags, 16).unwrap_or(RTF_REJECT);
+				*name != "lo" && flags & RTF_REJECT == 0
+			}
+			_ => false,
+		}
+	})
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn test_plan() {
+		let plan = |host, server| {
+			let plan = Ipv6Plan::new(host, server);
+			(plan.ipv6_disabled, plan.block_egress)
+		};
+		assert_eq!(plan(true, true), (false, false));
+		assert_eq!(plan(true, false), (true, true));
+		assert_eq!(plan(false, true), (true, false));
+		assert_eq!(plan(false, false), (true, false));
+	}
+
+	#[test]
+	fn test_has_global_address() {
+		let local_only = "00000000000000000000000000000001 01 80 10 80       lo
+fe80000000000000020c29fffe3a1b2c 02 40 20 80   enp3s0
+";
+		assert!(!has_global_address(local_only));
+
+		let global = format!(
+			"{}2a0104f8c2c0a1b2020c29fffe3a1b2c 02 40 00 00   enp3s0\n",
+			local_only
+		);
+		assert!(has_global_address(&global));
+	}
+
+	#[test]
+	fn test_has_default_route() {
+		let unreachable = "00000000000000000000000000000000 00 00000000000000000000000000000000 00 00000000000000000000000000000000 ffffffff 00000001 00000000 00200200       lo
+fe800000000000000000000000000000 40 00000000000000000000000000000000 00 00000000000000000000000000000000 00000100 00000001 00000000 00000001   enp3s0
+";
+		assert!(!has_default_route(unreachable));
+
+		let routed = format!(
+			"00000000000000000000000000000000 00 00000000000000000000000000000000 00 fe80000000000000000000000000001 00000400 00000002 00000000 00450003   enp3s0\n{}",
+			unreachable
+		);
+		assert!(has_default_route(&routed));
+	}
+}
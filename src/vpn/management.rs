@@ -0,0 +1,448 @@
+//! A client for openvpn's [management interface](https://openvpn.net/community-resources/management-interface/). Openvpn is started with `--management <socket> unix`, so a running connection can be inspected and controlled after this process detaches from it.
+//!
+//! The protocol is line based. Commands get a `SUCCESS:` or `ERROR:` line back, or several lines ending in `END`. Lines starting with `>` are notifications, which can arrive at any time, including in the middle of a command's reply.
+
+use anyhow::{anyhow, Context, Result};
+use std::{
+	collections::VecDeque,
+	io::{BufRead, BufReader, Write},
+	net::Ipv4Addr,
+	os::unix::net::UnixStream,
+	path::Path,
+	time::Duration,
+};
+
+/// How long to wait for openvpn to answer before giving up
+const READ_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// A connection to openvpn's management socket.
+pub struct ManagementClient {
+	reader: BufReader<UnixStream>,
+	writer: UnixStream,
+	/// Notifications read while waiting for a command's reply
+	pending: VecDeque<Notification>,
+}
+
+/// An asynchronous message from openvpn. These are the lines starting with `>`.
+#[derive(Debug, PartialEq)]
+pub enum Notification {
+	/// `>STATE:`, sent on every state change once `state on` was sent
+	State(State),
+	/// `>BYTECOUNT:`, sent periodically once `bytecount <n>` was sent
+	ByteCount {
+		/// Bytes received through the tunnel
+		received: u64,
+		/// Bytes sent through the tunnel
+		sent: u64,
+	},
+	/// `>HOLD:`, openvpn is waiting for `hold release`
+	Hold(String),
+	/// `>PASSWORD:`, openvpn needs credentials or reports that they were rejected
+	Password(PasswordRequest),
+	/// `>INFO:`, like the greeting sent on connect
+	Info(String),
+	/// Any other notification, with the `>` removed
+	Other(String),
+}
+
+/// One line of `state` output, like `1611234567,CONNECTED,SUCCESS,10.8.0.2,185.159.157.1,1194,,`
+#[derive(Debug, PartialEq)]
+pub struct State {
+	/// Unix time of the state change
+	pub time: i64,
+	/// Like `CONNECTING`, `RECONNECTING` or `CONNECTED`
+	pub name: String,
+	/// Extra detail, like `SUCCESS` or the reason for a reconnect
+	pub description: String,
+	/// Address of the tun device
+	pub local_ip: Option<Ipv4Addr>,
+	/// Address of the vpn server
+	pub remote_ip: Option<Ipv4Addr>,
+}
+
+/// The contents of a `>PASSWORD:` notification
+#[derive(Debug, PartialEq)]
+pub enum PasswordRequest {
+	/// `Need 'Auth' username/password`. Answer with [ManagementClient::send_credentials].
+	Need(String),
+	/// `Verification Failed: 'Auth'`
+	VerificationFailed(String),
+	/// Anything else
+	Other(String),
+}
+
+/// Signals that `signal` accepts
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum OpenVpnSignal {
+	/// Exit gracefully
+	SIGTERM,
+	/// Restart the connection without rereading the config
+	SIGUSR1,
+}
+
+impl ManagementClient {
+	/// Connects to the socket at `path` and reads openvpn's greeting
+	pub fn connect(path: &Path) -> Result<Self> {
+		let writer = UnixStream::connect(path).with_context(|| {
+			format!(
+				"Couldn't connect to the openvpn management socket {}",
+				path.display()
+			)
+		})?;
+		Self::new(writer)
+	}
+
+	fn new(writer: UnixStream) -> Result<Self> {
+		writer.set_read_timeout(Some(READ_TIMEOUT))?;
+		let mut client = Self {
+			reader: BufReader::new(writer.try_clone()?),
+			writer,
+			pending: VecDeque::new(),
+		};
+		match client.read_notification()? {
+			Notification::Info(_) => Ok(client),
+			other => Err(anyhow!("Unexpected greeting from openvpn: {:?}", other)),
+		}
+	}
+
+	/// The current connection state
+	pub fn state(&mut self) -> Result<State> {
+		let lines = self.multi_line_command("state")?;
+		let line = lines.first().context("openvpn didn't report its state")?;
+		parse_state(line)
+	}
+
+	/// Bytes received and sent through the tunnel. Asks openvpn for a single `>BYTECOUNT:` notification, so this can take up to a second.
+	pub fn bytecount(&mut self) -> Result<(u64, u64)> {
+		self.command("bytecount 1")?;
+		let mut skipped = vec![];
+		let counts = loop {
+			match self.next_notification()? {
+				Notification::ByteCount { received, sent } => break (received, sent),
+				other => skipped.push(other),
+			}
+		};
+		self.pending.extend(skipped);
+		self.command("bytecount 0")?;
+		Ok(counts)
+	}
+
+	/// Lets openvpn continue after a `>HOLD:` notification
+	pub fn hold_release(&mut self) -> Result<()> {
+		self.command("hold release").map(drop)
+	}
+
+	/// Sends a signal to openvpn, through the socket instead of as a unix signal
+	pub fn signal(&mut self, signal: OpenVpnSignal) -> Result<()> {
+		self.command(&format!("signal {:?}", signal)).map(drop)
+	}
+
+	/// Answers a [PasswordRequest::Need] for `kind`, usually `Auth`
+	pub fn send_credentials(&mut self, kind: &str, username: &str, password: &str) -> Result<()> {
+		self.command(&format!("username \"{}\" {}", kind, escape(username)))?;
+		self.command(&format!("password \"{}\" {}", kind, escape(password)))
+			.map(drop)
+	}
+
+	/// Releases openvpn from `--management-hold` and answers its request for credentials. Openvpn started with `--management-query-passwords` asks here instead of reading them from a file.
+	pub fn log_in(&mut self, username: &str, password: &str) -> Result<()> {
+		self.hold_release()?;
+		loop {
+			match self.next_notification()? {
+				Notification::Password(PasswordRequest::Need(kind)) => {
+					return self.send_credentials(&kind, username, password)
+				}
+				Notification::Password(PasswordRequest::VerificationFailed(kind)) => {
+					return Err(anyhow!("openvpn's '{}' credentials were rejected", kind))
+				}
+				_ => {}
+			}
+		}
+	}
+
+	/// Returns the next notification, waiting for one if none were queued while reading replies
+	pub fn next_notification(&mut self) -> Result<Notification> {
+		match self.pending.pop_front() {
+			Some(notification) => Ok(notification),
+			None => self.read_notification(),
+		}
+	}
+
+	/// Sends a command with a single line reply, returning the text after `SUCCESS:`
+	fn command(&mut self, command: &str) -> Result<String> {
+		self.send(command)?;
+		loop {
+			let line = self.read_line()?;
+			if let Some(notification) = line.strip_prefix('>') {
+				self.pending.push_back(parse_notification(notification));
+			} else if let Some(message) = line.strip_prefix("SUCCESS:") {
+				return Ok(message.trim().to_string());
+			} else if let Some(message) = line.strip_prefix("ERROR:") {
+				return Err(anyhow!(
+					"openvpn rejected `{}`: {}",
+					command,
+					message.trim()
+				));
+			}
+		}
+	}
+
+	/// Sends a command whose reply ends with `END`, returning the lines before it
+	fn multi_line_command(&mut self, command: &str) -> Result<Vec<String>> {
+		self.send(command)?;
+		let mut lines = vec![];
+		loop {
+			let line = self.read_line()?;
+			if let Some(notification) = line.strip_prefix('>') {
+				self.pending.push_back(parse_notification(notification));
+			} else if let Some(message) = line.strip_prefix("ERROR:") {
+				return Err(anyhow!(
+					"openvpn rejected `{}`: {}",
+					command,
+					message.trim()
+				));
+			} else if line == "END" {
+				return Ok(lines);
+			} else {
+				lines.push(line);
+			}
+		}
+	}
+
+	fn send(&mut self, command: &str) -> Result<()> {
+		writeln!(self.writer, "{}", command).context("Couldn't write to the management socket")
+	}
+
+	fn read_notification(&mut self) -> Result<Notification> {
+		loop {
+			if let Some(notification) = self.read_line()?.strip_prefix('>') {
+				return Ok(parse_notification(notification));
+			}
+		}
+	}
+
+	fn read_line(&mut self) -> Result<String> {
+		let mut line = String::new();
+		let read = self
+			.reader
+			.read_line(&mut line)
+			.context("Couldn't read from the management socket")?;
+		if read == 0 {
+			return Err(anyhow!("openvpn closed the management socket"));
+		}
+		Ok(line.trim_end_matches(&['\r', '\n'][..]).to_string())
+	}
+}
+
+/// Parses a notification with the leading `>` already removed
+fn parse_notification(line: &str) -> Notification {
+	let (kind, body) = match line.find(':') {
+		Some(i) => (&line[..i], &line[i + 1..]),
+		None => return Notification::Other(line.to_string()),
+	};
+	match kind {
+		"STATE" => parse_state(body)
+			.map(Notification::State)
+			.unwrap_or_else(|_| Notification::Other(line.to_string())),
+		"BYTECOUNT" => {
+			let mut counts = body.split(',').map(str::parse);
+			match (counts.next(), counts.next()) {
+				(Some(Ok(received)), Some(Ok(sent))) => Notification::ByteCount { received, sent },
+				_ => Notification::Other(line.to_string()),
+			}
+		}
+		"HOLD" => Notification::Hold(body.to_string()),
+		"PASSWORD" => Notification::Password(parse_password_request(body)),
+		"INFO" => Notification::Info(body.to_string()),
+		_ => Notification::Other(line.to_string()),
+	}
+}
+
+fn parse_state(line: &str) -> Result<State> {
+	let fields: Vec<_> = line.split(',').collect();
+	if fields.len() < 3 {
+		return Err(anyhow!("Malformed openvpn state: {}", line));
+	}
+	let ip = |i: usize| fields.get(i).and_then(|field| field.parse().ok());
+	Ok(State {
+		time: fields[0]
+			.parse()
+			.with_context(|| format!("Malformed openvpn state time: {}", line))?,
+		name: fields[1].to_string(),
+		description: fields[2].to_string(),
+		local_ip: ip(3),
+		remote_ip: ip(4),
+	})
+}
+
+fn parse_password_request(body: &str) -> PasswordRequest {
+	let kind = || body.split('\'').nth(1).unwrap_or_default().to_string();
+	if body.starts_with("Need ") {
+		PasswordRequest::Need(kind())
+	} else if body.starts_with("Verification Failed") {
+		PasswordRequest::VerificationFailed(kind())
+	} else {
+		PasswordRequest::Other(body.to_string())
+	}
+}
+
+/// Quotes a value for the management protocol, escaping `\` and `"`
+fn escape(value: &str) -> String {
+	format!("\"{}\"", value.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use std::{
+		io::{BufRead, BufReader, Write},
+		os::unix::net::UnixListener,
+		thread::{self, JoinHandle},
+	};
+	use tempfile::tempdir;
+
+	/// Stands in for openvpn. For each line the client sends, the fake server expects the next command in `script` and writes its reply. Returns the commands it received.
+	fn fake_openvpn(
+		path: &Path,
+		script: Vec<(&'static str, &'static str)>,
+	) -> JoinHandle<Vec<String>> {
+		let listener = UnixListener::bind(path).unwrap();
+		thread::spawn(move || {
+			let (mut stream, _) = listener.accept().unwrap();
+			let mut reader = BufReader::new(stream.try_clone().unwrap());
+			stream
+				.write_all(b">INFO:OpenVPN Management Interface Version 3 -- type 'help' for more info\r\n")
+				.unwrap();
+			let mut received = vec![];
+			for (expected, reply) in script {
+				let mut line = String::new();
+				reader.read_line(&mut line).unwrap();
+				let line = line.trim_end().to_string();
+				assert_eq!(line, expected);
+				received.push(line);
+				stream.write_all(reply.as_bytes()).unwrap();
+			}
+			received
+		})
+	}
+
+	#[test]
+	fn test_state() -> Result<()> {
+		let dir = tempdir()?;
+		let path = dir.path().join("management.sock");
+		let server = fake_openvpn(
+			&path,
+			vec![(
+				"state",
+				">HOLD:Waiting for hold release:0\r\n1611234567,CONNECTED,SUCCESS,10.8.0.2,185.159.157.1,1194,,\r\nEND\r\n",
+			)],
+		);
+
+		let mut client = ManagementClient::connect(&path)?;
+		let state = client.state()?;
+		assert_eq!(
+			state,
+			State {
+				time: 1611234567,
+				name: "CONNECTED".into(),
+				description: "SUCCESS".into(),
+				local_ip: Some(Ipv4Addr::new(10, 8, 0, 2)),
+				remote_ip: Some(Ipv4Addr::new(185, 159, 157, 1)),
+			}
+		);
+		assert_eq!(
+			client.next_notification()?,
+			Notification::Hold("Waiting for hold release:0".into())
+		);
+		server.join().unwrap();
+		Ok(())
+	}
+
+	#[test]
+	fn test_bytecount() -> Result<()> {
+		let dir = tempdir()?;
+		let path = dir.path().join("management.sock");
+		let server = fake_openvpn(
+			&path,
+			vec![
+				(
+					"bytecount 1",
+					"SUCCESS: bytecount interval changed\r\n>STATE:1611234567,RECONNECTING,ping-restart,,,,,\r\n>BYTECOUNT:2048,1024\r\n",
+				),
+				("bytecount 0", "SUCCESS: bytecount interval changed\r\n"),
+			],
+		);
+
+		let mut client = ManagementClient::connect(&path)?;
+		assert_eq!(client.bytecount()?, (2048, 1024));
+		match client.next_notification()? {
+			Notification::State(state) => assert_eq!(state.name, "RECONNECTING"),
+			other => panic!("{:?}", other),
+		}
+		server.join().unwrap();
+		Ok(())
+	}
+
+	#[test]
+	fn test_signal_and_credentials() -> Result<()> {
+		let dir = tempdir()?;
+		let path = dir.path().join("management.sock");
+		let server = fake_openvpn(
+			&path,
+			vec![
+				("hold release", ">PASSWORD:Need 'Auth' username/password\r\nSUCCESS: hold release succeeded\r\n"),
+				("username \"Auth\" \"user+plc\"", "SUCCESS: 'Auth' username entered, but not yet verified\r\n"),
+				("password \"Auth\" \"pa\\\"ss\"", "SUCCESS: 'Auth' password entered, but not yet verified\r\n"),
+				("signal SIGUSR1", "SUCCESS: signal SIGUSR1 thrown\r\n"),
+				("signal SIGTERM", "ERROR: signal already queued\r\n"),
+			],
+		);
+
+		let mut client = ManagementClient::connect(&path)?;
+		client.hold_release()?;
+		assert_eq!(
+			client.next_notification()?,
+			Notification::Password(PasswordRequest::Need("Auth".into()))
+		);
+		client.send_credentials("Auth", "user+plc", "pa\"ss")?;
+		client.signal(OpenVpnSignal::SIGUSR1)?;
+		assert!(client.signal(OpenVpnSignal::SIGTERM).is_err());
+		server.join().unwrap();
+		Ok(())
+	}
+
+	#[test]
+	fn test_log_in() -> Result<()> {
+		let dir = tempdir()?;
+		let path = dir.path().join("management.sock");
+		let server = fake_openvpn(
+			&path,
+			vec![
+				("hold release", ">HOLD:Waiting for hold release:0\r\nSUCCESS: hold release succeeded\r\n>STATE:1611234567,WAIT,,,,,,\r\n>PASSWORD:Need 'Auth' username/password\r\n"),
+				("username \"Auth\" \"user+plc\"", "SUCCESS: 'Auth' username entered, but not yet verified\r\n"),
+				("password \"Auth\" \"pass\"", "SUCCESS: 'Auth' password entered, but not yet verified\r\n"),
+			],
+		);
+
+		let mut client = ManagementClient::connect(&path)?;
+		client.log_in("user+plc", "pass")?;
+		assert_eq!(server.join().unwrap().len(), 3);
+		Ok(())
+	}
+
+	#[test]
+	fn test_parse_notification() {
+		assert_eq!(
+			parse_notification("PASSWORD:Verification Failed: 'Auth'"),
+			Notification::Password(PasswordRequest::VerificationFailed("Auth".into()))
+		);
+		assert_eq!(
+			parse_notification("BYTECOUNT:x,1"),
+			Notification::Other("BYTECOUNT:x,1".into())
+		);
+		assert_eq!(
+			parse_notification("FATAL:cannot allocate TUN/TAP dev"),
+			Notification::Other("FATAL:cannot allocate TUN/TAP dev".into())
+		);
+	}
+}
@@ -0,0 +1,517 @@
+use std::{
+	fmt::{self, Display},
+	fs::{read_to_string, write},
+	io::ErrorKind,
+	net::{Ipv4Addr, SocketAddr, ToSocketAddrs},
+	path::Path,
+	str::FromStr,
+};
+
+use anyhow::{anyhow, bail, Context, Error, Result};
+use serde::{Deserialize, Serialize};
+
+use super::IpNm;
+
+/// An ipv4 network in CIDR notation, like `192.168.1.0/24`. A bare address is a `/32`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Cidr {
+	addr: Ipv4Addr,
+	prefix: u8,
+}
+
+impl Cidr {
+	fn mask(&self) -> u32 {
+		u32::MAX
+			.checked_shl(32 - u32::from(self.prefix))
+			.unwrap_or(0)
+	}
+
+	/// Whether the two networks share any address
+	pub(crate) fn overlaps(&self, other: &Cidr) -> bool {
+		let mask = self.mask() & other.mask();
+		u32::from(self.addr) & mask == u32::from(other.addr) & mask
+	}
+}
+
+impl FromStr for Cidr {
+	type Err = Error;
+
+	fn from_str(s: &str) -> Result<Self> {
+		let (addr, prefix) = match s.split_once('/') {
+			Some((addr, prefix)) => (addr, prefix),
+			None => (s, "32"),
+		};
+		let addr: Ipv4Addr = addr
+			.parse()
+			.map_err(|_| anyhow!("{} isn't an ipv4 address", addr))?;
+		let prefix: u8 = prefix
+			.parse()
+			.ok()
+			.filter(|prefix| *prefix <= 32)
+			.ok_or_else(|| anyhow!("{} isn't a prefix length between 0 and 32", prefix))?;
+
+		let cidr = Self { addr, prefix };
+		let network = Ipv4Addr::from(u32::from(addr) & cidr.mask());
+		if network != addr {
+			bail!(
+				"{} has host bits set. Did you mean {}/{}?",
+				s,
+				network,
+				prefix
+			);
+		}
+		Ok(cidr)
+	}
+}
+
+impl Display for Cidr {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		write!(f, "{}/{}", self.addr, self.prefix)
+	}
+}
+
+impl From<Cidr> for IpNm {
+	fn from(cidr: Cidr) -> Self {
+		Self {
+			ip: cidr.addr,
+			nm: cidr.mask().into(),
+		}
+	}
+}
+
+impl From<Ipv4Addr> for Cidr {
+	fn from(addr: Ipv4Addr) -> Self {
+		Self { addr, prefix: 32 }
+	}
+}
+
+impl From<&IpNm> for Cidr {
+	fn from(ip_nm: &IpNm) -> Self {
+		Self {
+			addr: ip_nm.ip,
+			prefix: u32::from(ip_nm.nm).count_ones() as u8,
+		}
+	}
+}
+
+/// The fewest networks that cover every ipv4 address outside `excluded`. Wireguard can't route around a network, so excluding one means routing everything else.
+pub(crate) fn complement(excluded: &[Cidr]) -> Vec<Cidr> {
+	let mut remaining = vec![];
+	let mut pending = vec![Cidr {
+		addr: Ipv4Addr::UNSPECIFIED,
+		prefix: 0,
+	}];
+	while let Some(network) = pending.pop() {
+		let overlapping: Vec<_> = excluded.iter().filter(|e| e.overlaps(&network)).collect();
+		if overlapping.is_empty() {
+			remaining.push(network);
+		} else if overlapping.iter().all(|e| e.prefix > network.prefix) {
+			// Partly excluded, so look at each half
+			let half = 1 << (31 - network.prefix);
+			let prefix = network.prefix + 1;
+			pending.push(Cidr {
+				addr: (u32::from(network.addr) | half).into(),
+				prefix,
+			});
+			pending.push(Cidr {
+				addr: network.addr,
+				prefix,
+			});
+		}
+	}
+	remaining
+}
+
+/// One line of the split tunnel list: a network, or a domain name that is resolved each time a connection is planned
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SplitEntry {
+	/// A network in CIDR notation
+	Network(Cidr),
+	/// A domain name, stored in lowercase without a trailing dot
+	Domain(String),
+}
+
+impl SplitEntry {
+	/// Whether the two entries would route the same addresses. Domains are only compared by name.
+	fn conflicts(&self, other: &SplitEntry) -> bool {
+		match (self, other) {
+			(Self::Network(a), Self::Network(b)) => a.overlaps(b),
+			(a, b) => a == b,
+		}
+	}
+}
+
+impl FromStr for SplitEntry {
+	type Err = Error;
+
+	fn from_str(s: &str) -> Result<Self> {
+		// Anything made of digits, dots and slashes is meant as a network, so it gets the CIDR errors
+		if s.chars()
+			.all(|c| c.is_ascii_digit() || c == '.' || c == '/')
+		{
+			return Ok(Self::Network(s.parse()?));
+		}
+		let domain = s.trim_end_matches('.').to_ascii_lowercase();
+		let valid_label = |label: &str| {
+			!label.is_empty()
+				&& label.len() <= 63
+				&& !label.starts_with('-')
+				&& !label.ends_with('-')
+				&& label.chars().all(|c| c.is_ascii_alphanumeric() || c == '-')
+		};
+		if domain.len() > 253 || !domain.split('.').all(valid_label) {
+			bail!("{} is neither a network nor a domain name", s);
+		}
+		Ok(Self::Domain(domain))
+	}
+}
+
+impl Display for SplitEntry {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		match self {
+			Self::Network(cidr) => cidr.fmt(f),
+			Self::Domain(domain) => f.write_str(domain),
+		}
+	}
+}
+
+/// A domain from the split tunnel list and the ipv4 addresses it resolved to. Empty if it couldn't be resolved.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub struct ResolvedDomain {
+	pub(crate) domain: String,
+	pub(crate) addresses: Vec<Ipv4Addr>,
+}
+
+impl Display for ResolvedDomain {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		if self.addresses.is_empty() {
+			return write!(f, "{} (unresolved)", self.domain);
+		}
+		let addresses: Vec<_> = self.addresses.iter().map(Ipv4Addr::to_string).collect();
+		write!(f, "{} ({})", self.domain, addresses.join(", "))
+	}
+}
+
+/// Looks up the ipv4 addresses of `domain` with the system resolver
+pub(crate) fn lookup_ipv4(domain: &str) -> Result<Vec<Ipv4Addr>> {
+	let mut addresses = vec![];
+	for addr in (domain, 0)
+		.to_socket_addrs()
+		.with_context(|| format!("Couldn't resolve {}", domain))?
+	{
+		if let SocketAddr::V4(addr) = addr {
+			if !addresses.contains(addr.ip()) {
+				addresses.push(*addr.ip());
+			}
+		}
+	}
+	if addresses.is_empty() {
+		bail!("{} has no ipv4 address", domain);
+	}
+	Ok(addresses)
+}
+
+/// How the split tunnel list is applied to a connection
+#[derive(Debug, Serialize, Deserialize, PartialEq, Eq, Copy, Clone, Default)]
+pub enum SplitMode {
+	/// The listed networks bypass the vpn. Everything else goes through it.
+	#[default]
+	Exclude,
+	/// Only the listed networks go through the vpn. The default route stays on the local network.
+	IncludeOnly,
+}
+
+impl Display for SplitMode {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		f.write_str(match self {
+			Self::Exclude => "exclude",
+			Self::IncludeOnly => "include-only",
+		})
+	}
+}
+
+impl FromStr for SplitMode {
+	type Err = String;
+	fn from_str(s: &str) -> Result<Self, Self::Err> {
+		match s.to_ascii_lowercase().replace('_', "-").as_str() {
+			"exclude" => Ok(Self::Exclude),
+			"include-only" | "include" => Ok(Self::IncludeOnly),
+			_ => Err("String must be exclude or include-only".into()),
+		}
+	}
+}
+
+/// The networks and domains that bypass the vpn, stored one [SplitEntry] per line in the [split tunnel file](crate::constants::SPLIT_TUNNEL_FILE). Lines starting with `#` are comments.
+#[derive(Debug, Default, PartialEq)]
+pub(crate) struct SplitTunnelList {
+	entries: Vec<SplitEntry>,
+}
+
+impl SplitTunnelList {
+	/// Reads the list at `path`. A missing file is an empty list.
+	pub(crate) fn load(path: &Path) -> Result<Self> {
+		let contents = match read_to_string(path) {
+			Ok(contents) => contents,
+			Err(e) if e.kind() == ErrorKind::NotFound => return Ok(Self::default()),
+			Err(e) => return Err(e).with_context(|| format!("Couldn't read {}", path.display())),
+		};
+		let entries = contents
+			.lines()
+			.enumerate()
+			.map(|(i, line)| (i, line.trim()))
+			.filter(|(_, line)| !line.is_empty() && !line.starts_with('#'))
+			.map(|(i, line)| {
+				line.parse()
+					.with_context(|| format!("{} line {}", path.display(), i + 1))
+			})
+			.collect::<Result<_>>()?;
+		Ok(Self { entries })
+	}
+
+	pub(crate) fn save(&self, path: &Path) -> Result<()> {
+		let contents: String = self.entries.iter().map(|e| format!("{}\n", e)).collect();
+		write(path, contents).with_context(|| format!("Couldn't write {}", path.display()))
+	}
+
+	pub(crate) fn entries(&self) -> &[SplitEntry] {
+		&self.entries
+	}
+
+	/// Adds `entry`, unless it overlaps a network or repeats a domain already in the list
+	pub(crate) fn add(&mut self, entry: SplitEntry) -> Result<()> {
+		if let Some(existing) = self.entries.iter().find(|e| e.conflicts(&entry)) {
+			if *existing == entry {
+				bail!("{} is already in the list", entry);
+			}
+			bail!(
+				"{} overlaps {}, which is already in the list",
+				entry,
+				existing
+			);
+		}
+		self.entries.push(entry);
+		Ok(())
+	}
+
+	pub(crate) fn remove(&mut self, entry: &SplitEntry) -> Result<()> {
+		let len = self.entries.len();
+		self.entries.retain(|e| e != entry);
+		if self.entries.len() == len {
+			bail!("{} isn't in the list", entry);
+		}
+		Ok(())
+	}
+
+	pub(crate) fn clear(&mut self) {
+		self.entries.clear();
+	}
+
+	/// The entries as routes for the openvpn config. Domains are resolved with `lookup` and routed as single addresses. A domain that can't be resolved is left out with a warning, rather than failing the connection.
+	pub(crate) fn resolve<F>(&self, mut lookup: F) -> (Vec<IpNm>, Vec<ResolvedDomain>)
+	where
+		F: FnMut(&str) -> Result<Vec<Ipv4Addr>>,
+	{
+		let mut routes = vec![];
+		let mut domains = vec![];
+		for entry in &self.entries {
+			let addresses = match entry {
+				SplitEntry::Network(cidr) => {
+					routes.push(IpNm::from(*cidr));
+					continue;
+				}
+				SplitEntry::Domain(domain) => lookup(domain).unwrap_or_else(|e| {
+					eprintln!("Warning: {:#}, so it is left out of the split tunnel", e);
+					vec![]
+				}),
+			};
+			for addr in &addresses {
+				let route = IpNm::from(Cidr::from(*addr));
+				if !routes.contains(&route) {
+					routes.push(route);
+				}
+			}
+			domains.push(ResolvedDomain {
+				domain: entry.to_string(),
+				addresses,
+			});
+		}
+		(routes, domains)
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use tempfile::tempdir;
+
+	#[test]
+	fn test_parse_cidr() {
+		let cidr: Cidr = "192.168.1.0/24".parse().unwrap();
+		assert_eq!(cidr.to_string(), "192.168.1.0/24");
+		assert_eq!(
+			"10.0.0.1".parse::<Cidr>().unwrap().to_string(),
+			"10.0.0.1/32"
+		);
+		assert_eq!(
+			"0.0.0.0/0".parse::<Cidr>().unwrap().to_string(),
+			"0.0.0.0/0"
+		);
+
+		let err = "192.168.1.5/24".parse::<Cidr>().unwrap_err();
+		assert!(err.to_string().contains("Did you mean 192.168.1.0/24?"));
+		assert!("192.168.1.0/33".parse::<Cidr>().is_err());
+		assert!("192.168.1/24".parse::<Cidr>().is_err());
+		assert!("example.com".parse::<Cidr>().is_err());
+	}
+
+	#[test]
+	fn test_overlaps() {
+		let cidr = |s: &str| s.parse::<Cidr>().unwrap();
+		assert!(cidr("10.0.0.0/8").overlaps(&cidr("10.1.2.0/24")));
+		assert!(cidr("10.1.2.0/24").overlaps(&cidr("10.0.0.0/8")));
+		assert!(cidr("10.1.2.3").overlaps(&cidr("10.1.2.3/32")));
+		assert!(!cidr("10.0.0.0/8").overlaps(&cidr("11.0.0.0/8")));
+		assert!(cidr("0.0.0.0/0").overlaps(&cidr("192.168.1.1")));
+	}
+
+	#[test]
+	fn test_complement() {
+		let cidrs =
+			|list: &[&str]| -> Vec<Cidr> { list.iter().map(|s| s.parse().unwrap()).collect() };
+		assert_eq!(complement(&[]), cidrs(&["0.0.0.0/0"]));
+		assert_eq!(
+			complement(&cidrs(&["128.0.0.0/2"])),
+			cidrs(&["0.0.0.0/1", "192.0.0.0/2"])
+		);
+		assert!(complement(&cidrs(&["0.0.0.0/0"])).is_empty());
+
+		let excluded = cidrs(&["10.0.0.0/8", "192.168.1.7"]);
+		let remaining = complement(&excluded);
+		// 128.0.0.0/1 is split up to make room for the single address
+		assert_eq!(remaining.len(), 7 + 31);
+		for network in &remaining {
+			assert!(!excluded.iter().any(|e| e.overlaps(network)), "{}", network);
+		}
+		let covered: u64 = remaining.iter().map(|n| 1 << (32 - n.prefix)).sum();
+		assert_eq!(covered, (1 << 32) - (1 << 24) - 1);
+	}
+
+	#[test]
+	fn test_split_mode() {
+		assert_eq!("include-only".parse(), Ok(SplitMode::IncludeOnly));
+		assert_eq!("Include_Only".parse(), Ok(SplitMode::IncludeOnly));
+		assert_eq!("exclude".parse(), Ok(SplitMode::Exclude));
+		assert!("everything".parse::<SplitMode>().is_err());
+		assert_eq!(SplitMode::IncludeOnly.to_string(), "include-only");
+	}
+
+	#[test]
+	fn test_ip_nm() {
+		let ip_nm = IpNm::from("172.16.0.0/12".parse::<Cidr>().unwrap());
+		assert_eq!(ip_nm.ip, Ipv4Addr::new(172, 16, 0, 0));
+		assert_eq!(ip_nm.nm, Ipv4Addr::new(255, 240, 0, 0));
+		let ip_nm = IpNm::from("0.0.0.0/0".parse::<Cidr>().unwrap());
+		assert_eq!(ip_nm.nm, Ipv4Addr::UNSPECIFIED);
+		assert_eq!(Cidr::from(&ip_nm).to_string(), "0.0.0.0/0");
+	}
+
+	#[test]
+	fn test_list() -> Result<()> {
+		let dir = tempdir()?;
+		let path = dir.path().join("split_tunnel.txt");
+		let mut list = SplitTunnelList::load(&path)?;
+		assert!(list.entries().is_empty());
+
+		list.add("192.168.0.0/16".parse()?)?;
+		list.add("10.0.0.1".parse()?)?;
+		list.add("Git.Example.com.".parse()?)?;
+		let err = list.add("192.168.1.0/24".parse()?).unwrap_err();
+		assert_eq!(
+			err.to_string(),
+			"192.168.1.0/24 overlaps 192.168.0.0/16, which is already in the list"
+		);
+		let err = list.add("git.example.com".parse()?).unwrap_err();
+		assert_eq!(err.to_string(), "git.example.com is already in the list");
+		list.save(&path)?;
+		assert_eq!(
+			read_to_string(&path)?,
+			"192.168.0.0/16\n10.0.0.1/32\ngit.example.com\n"
+		);
+
+		let mut loaded = SplitTunnelList::load(&path)?;
+		assert_eq!(loaded, list);
+		loaded.remove(&"10.0.0.1".parse()?)?;
+		assert!(loaded.remove(&"10.0.0.1".parse()?).is_err());
+		loaded.remove(&"git.example.com".parse()?)?;
+		assert_eq!(loaded.entries().len(), 1);
+		loaded.clear();
+		assert!(loaded.entries().is_empty());
+		Ok(())
+	}
+
+	#[test]
+	fn test_parse_entry() {
+		let entry = |s: &str| s.parse::<SplitEntry>();
+		assert_eq!(
+			entry("10.0.0.0/8").unwrap(),
+			SplitEntry::Network("10.0.0.0/8".parse().unwrap())
+		);
+		assert_eq!(
+			entry("Bank.example.").unwrap(),
+			SplitEntry::Domain("bank.example".into())
+		);
+		assert_eq!(entry("intranet").unwrap().to_string(), "intranet");
+		let err = entry("10.0.0.5/8").unwrap_err();
+		assert!(err.to_string().contains("Did you mean"), "{}", err);
+		assert!(entry("not a network").is_err());
+		assert!(entry("-bad.example.com").is_err());
+		assert!(entry("a..b").is_err());
+	}
+
+	#[test]
+	fn test_resolve() -> Result<()> {
+		let mut list = SplitTunnelList::default();
+		list.add("10.0.0.0/8".parse()?)?;
+		list.add("git.example.com".parse()?)?;
+		list.add("mirror.example.com".parse()?)?;
+		list.add("gone.example.com".parse()?)?;
+
+		let mut lookups = vec![];
+		let (routes, domains) = list.resolve(|domain| {
+			lookups.push(domain.to_string());
+			match domain {
+				"git.example.com" => Ok(vec![
+					Ipv4Addr::new(192, 0, 2, 1),
+					Ipv4Addr::new(192, 0, 2, 2),
+				]),
+				"mirror.example.com" => Ok(vec![Ipv4Addr::new(192, 0, 2, 2)]),
+				_ => bail!("Couldn't resolve {}", domain),
+			}
+		});
+		assert_eq!(
+			lookups,
+			["git.example.com", "mirror.example.com", "gone.example.com"]
+		);
+		let expected: Vec<IpNm> = ["10.0.0.0/8", "192.0.2.1", "192.0.2.2"]
+			.iter()
+			.map(|s| s.parse::<Cidr>().unwrap().into())
+			.collect();
+		assert_eq!(routes, expected);
+		assert_eq!(
+			domains[0].to_string(),
+			"git.example.com (192.0.2.1, 192.0.2.2)"
+		);
+		assert_eq!(domains[2].to_string(), "gone.example.com (unresolved)");
+		Ok(())
+	}
+
+	#[test]
+	fn test_load_invalid() -> Result<()> {
+		let dir = tempdir()?;
+		let path = dir.path().join("split_tunnel.txt");
+		write(&path, "# office\n10.0.0.0/8\n\nnot a network\n")?;
+		let err = SplitTunnelList::load(&path).unwrap_err();
+		assert!(format!("{:#}", err).contains("line 4"), "{:#}", err);
+		Ok(())
+	}
+}
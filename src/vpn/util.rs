@@ -5,6 +5,12 @@ use std::{net::Ipv4Addr, str::FromStr};
 use strum_macros::{Display, EnumIter};
 use url::Url;
 
+use super::{
+	backend::Handle,
+	split_tunnel::{ResolvedDomain, SplitMode},
+};
+use crate::utils::{Features, LogicalServer};
+
 /// Holds all application state
 ///
 /// Holds current connection information and settings for the current (only) user
@@ -16,35 +22,62 @@ pub struct Config {
 	pub connection_info: Option<ConnectionInfo>,
 	/// Random extra info
 	pub metadata: MetaData,
+	/// The server of the last successful connection. Unlike connection_info, this survives a disconnect.
+	#[serde(default)]
+	pub last_connection: Option<LastConnection>,
 }
 
 /// Holds all user settings. See the docs on each field to learn more.
 #[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
 pub struct UserConfig {
 	pub(crate) username: String,
-	pub(crate) password: String,
+	/// Where the password is kept. The config file never holds it, see [secrets](crate::secrets).
+	#[serde(default)]
+	pub(crate) password_store: PasswordStore,
+	/// The plaintext password of configs written before [password_store](Self::password_store) existed. It is moved into the store when the config is loaded, and never written back.
+	#[serde(default, rename = "password", skip_serializing)]
+	pub(crate) legacy_password: String,
+	/// Run with `sh -c` to get the password from a password manager, like `pass show protonvpn`. Takes precedence over [password_store](Self::password_store).
+	#[serde(default)]
+	pub(crate) password_command: Option<String>,
 	pub(crate) tier: PlanTier,
 	pub(crate) protocol: ConnectionProtocol,
 	/// A recommended security setting that enables using Proton VPN's dns servers, or your own. In other words, don't use the dns servers from your operating system / internet service provider
 	pub(crate) dns_leak_protection: bool,
 	/// This setting is only referenced if the dns_leak_protection is enabled
 	pub(crate) custom_dns: Vec<Ipv4Addr>,
-	pub(crate) check_update_interval: u8,
-	pub(crate) killswitch: u8,
+	/// Minutes before the cached server list is downloaded again. Replaces `check_update_interval`, which older configs left at 3 and which is ignored now, since it wasn't a number of minutes.
+	#[serde(default = "UserConfig::default_server_cache_minutes")]
+	pub(crate) server_cache_minutes: u8,
+	/// Whether to block traffic outside the tunnel. Older configs stored this as a number, which is still accepted.
+	#[serde(deserialize_with = "KillSwitch::deserialize_legacy")]
+	pub(crate) killswitch: KillSwitch,
 	pub(crate) split_tunnel: bool,
+	/// Whether the kill switch lets through the local network, like printers and file shares
+	#[serde(default)]
+	pub(crate) allow_lan: bool,
+	/// Which vpn software connects. Only openvpn uses [protocol](Self::protocol), wireguard always runs over UDP.
+	#[serde(default)]
+	pub(crate) backend: Backend,
+	/// The wireguard public key the user confirmed is registered with their account. Servers reject any other key, so wireguard can't be used until this matches the generated key.
+	#[serde(default)]
+	pub(crate) wireguard_public_key: Option<String>,
 	// Remove this field. It can't change. Its always the default (see impl Default)
 	pub(crate) api_domain: Url,
 }
 
 impl UserConfig {
 	/// Constructor: All other params assume default values. Use the setters in [super::settings] for mutation
-	pub fn new(username: String, password: String) -> Self {
+	pub fn new(username: String) -> Self {
 		Self {
 			username,
-			password,
 			..Default::default()
 		}
 	}
+
+	fn default_server_cache_minutes() -> u8 {
+		15
+	}
 }
 
 /// Creates unusable initial state. Must set the username and password fields (is initially None)
@@ -52,14 +85,19 @@ impl Default for UserConfig {
 	fn default() -> Self {
 		Self {
 			username: String::new(),
-			password: String::new(),
+			password_store: PasswordStore::Keyring,
+			legacy_password: String::new(),
+			password_command: None,
 			tier: PlanTier::Free,
 			protocol: ConnectionProtocol::UDP,
 			dns_leak_protection: true,
 			custom_dns: Vec::with_capacity(3),
-			check_update_interval: 3,
-			killswitch: 0,
+			server_cache_minutes: Self::default_server_cache_minutes(),
+			killswitch: KillSwitch::Off,
 			split_tunnel: false,
+			allow_lan: false,
+			backend: Backend::OpenVpn,
+			wireguard_public_key: None,
 			api_domain: Url::parse("https://api.protonvpn.ch")
 				.context("Failed to parse protonvpn api url")
 				.unwrap(),
@@ -91,17 +129,71 @@ impl From<u8> for PlanTier {
 }
 
 /// The connection protocol to use for vpn connections. The default is UDP
-#[derive(Debug, Serialize, Deserialize, PartialEq, Copy, Clone, EnumIter, Display)]
+#[derive(Debug, Serialize, Deserialize, PartialEq, Copy, Clone, EnumIter, Display, Default)]
 pub enum ConnectionProtocol {
 	/// Default variant, [User Datagram Protocol](https://www.cloudflare.com/learning/ddos/glossary/user-datagram-protocol-udp/)
+	#[default]
 	UDP,
 	/// [Transmission Control Protocol](https://www.cloudflare.com/learning/ddos/glossary/tcp-ip/)
 	TCP,
 }
 
-impl Default for ConnectionProtocol {
-	fn default() -> Self {
-		Self::UDP
+/// The vpn software used to connect. See [wireguard](super::wireguard).
+#[derive(Debug, Serialize, Deserialize, PartialEq, Eq, Copy, Clone, EnumIter, Display, Default)]
+pub enum Backend {
+	/// Default variant, runs `openvpn` with a generated config
+	#[default]
+	OpenVpn,
+	/// Brings up a wireguard interface with `wg-quick`
+	WireGuard,
+}
+
+/// Where the ProtonVPN password is kept. See [secrets](crate::secrets).
+#[derive(Debug, Serialize, Deserialize, PartialEq, Eq, Copy, Clone, Display, Default)]
+pub enum PasswordStore {
+	/// Default variant, the desktop's keyring through the Secret Service api
+	#[default]
+	#[strum(serialize = "system keyring")]
+	Keyring,
+	/// A file in the config dir, encrypted with a passphrase
+	#[strum(serialize = "encrypted password file")]
+	EncryptedFile,
+}
+
+/// When to block traffic that doesn't go through the vpn. See [firewall](super::firewall).
+#[derive(Debug, Serialize, Deserialize, PartialEq, Eq, Copy, Clone, EnumIter, Display, Default)]
+pub enum KillSwitch {
+	/// Never install any firewall rules
+	#[default]
+	Off,
+	/// Block traffic outside the tunnel while connected. The rules are removed on disconnect.
+	On,
+	/// Like [KillSwitch::On], but the rules stay in place after disconnecting, until the next connection or until the kill switch is turned off.
+	AlwaysOn,
+}
+
+impl KillSwitch {
+	fn deserialize_legacy<'de, D>(deserializer: D) -> Result<Self, D::Error>
+	where
+		D: serde::Deserializer<'de>,
+	{
+		#[derive(Deserialize)]
+		#[serde(untagged)]
+		enum Repr {
+			Legacy(u8),
+			Named(KillSwitch),
+		}
+
+		match Repr::deserialize(deserializer)? {
+			Repr::Legacy(0) => Ok(Self::Off),
+			Repr::Legacy(1) => Ok(Self::On),
+			Repr::Legacy(2) => Ok(Self::AlwaysOn),
+			Repr::Legacy(n) => Err(serde::de::Error::custom(format!(
+				"invalid kill switch setting {}",
+				n
+			))),
+			Repr::Named(k) => Ok(k),
+		}
 	}
 }
 
@@ -119,7 +211,11 @@ impl FromStr for ConnectionProtocol {
 /// Random extra info used by the application.
 #[derive(Serialize, Deserialize, Debug, PartialEq)]
 pub struct MetaData {
+	/// Hash of the resolv.conf written for dns leak protection. See [ResolvConf](crate::vpn::dns::ResolvConf).
 	pub(crate) resolvconf_hash: Option<String>,
+	/// The link whose dns was set through systemd-resolved for dns leak protection
+	#[serde(default)]
+	pub(crate) resolved_link: Option<String>,
 	/// Time of the last call to the `/vpn/logicals` api endpoint. See [get_servers()](crate::utils::get_servers).
 	///
 	/// If config could not be found, this defaults to 0 milliseconds, as a sort of Time::MIN
@@ -130,16 +226,168 @@ impl Default for MetaData {
 	fn default() -> Self {
 		Self {
 			resolvconf_hash: None,
+			resolved_link: None,
 			last_api_pull: Utc.timestamp_millis(0),
 		}
 	}
 }
 
+/// What `reconnect` needs to connect to the last used server again.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct LastConnection {
+	pub(crate) server_id: String,
+	pub(crate) server_name: String,
+	pub(crate) protocol: ConnectionProtocol,
+	/// The split mode chosen on the command line, if any
+	#[serde(default)]
+	pub(crate) split_mode: Option<SplitMode>,
+	/// Where the server exits, so a replacement can exit there too. Missing in older configs.
+	#[serde(default)]
+	pub(crate) exit_country: Option<String>,
+	/// The server's features, which a replacement must also have. Missing in older configs.
+	#[serde(default)]
+	pub(crate) features: Option<Features>,
+}
+
 /// Information about the current vpn connection.
 #[derive(Serialize, Deserialize, Debug)]
 pub struct ConnectionInfo {
 	pub(crate) server_id: String,
+	pub(crate) server_name: String,
+	pub(crate) entry_country: String,
+	pub(crate) exit_country: String,
 	pub(crate) protocol: ConnectionProtocol,
-	pub(crate) dns_server: String,
-	pub(crate) connected_time: String,
+	/// The dns server pushed by the vpn server, once it is known
+	pub(crate) dns_server: Option<Ipv4Addr>,
+	/// Whether ipv6 is blocked outside the tunnel for this session. See [FirewallBackend::block_ipv6](crate::vpn::firewall::FirewallBackend::block_ipv6).
+	#[serde(default)]
+	pub(crate) ipv6_blocked: bool,
+	pub(crate) connected_time: DateTime<Utc>,
+	/// Name of the tun device, see [TUN_DEVICE](crate::constants::TUN_DEVICE) and [WG_DEVICE](crate::constants::WG_DEVICE)
+	pub(crate) interface: String,
+	/// What the backend needs to find the tunnel again
+	#[serde(flatten)]
+	pub(crate) handle: Handle,
+	/// The split tunnel list's domains, as they were resolved for this session. Flattening makes this a map, which toml only accepts with tables last.
+	#[serde(default)]
+	pub(crate) split_domains: Vec<ResolvedDomain>,
+}
+
+impl ConnectionInfo {
+	/// A session that just came up
+	pub(crate) fn new(
+		server: &LogicalServer,
+		protocol: ConnectionProtocol,
+		interface: &str,
+		handle: Handle,
+	) -> Self {
+		Self {
+			server_id: server.id.clone(),
+			server_name: server.name.clone(),
+			entry_country: server.entry_country.clone(),
+			exit_country: server.exit_country.clone(),
+			protocol,
+			dns_server: None,
+			ipv6_blocked: false,
+			split_domains: vec![],
+			connected_time: Utc::now(),
+			interface: interface.into(),
+			handle,
+		}
+	}
+
+	/// Like `UDP`, or `WireGuard`, which always runs over UDP
+	pub(crate) fn transport(&self) -> String {
+		match self.handle {
+			Handle::WireGuard { .. } => Backend::WireGuard.to_string(),
+			_ => self.protocol.to_string(),
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use tempfile::tempdir;
+
+	#[test]
+	fn test_config_roundtrip() -> Result<()> {
+		let dir = tempdir()?;
+		let path = dir.path().join("config.toml");
+		let config = Config {
+			connection_info: Some(ConnectionInfo {
+				split_domains: vec![ResolvedDomain {
+					domain: "git.example.com".into(),
+					addresses: vec![Ipv4Addr::new(192, 0, 2, 1)],
+				}],
+				..ConnectionInfo::new(
+					&LogicalServer::mock("CH#1", 2, 1.0, 10),
+					ConnectionProtocol::TCP,
+					"proton0",
+					Handle::OpenVpn {
+						pid: 42,
+						management_socket: dir.path().join("management.sock"),
+					},
+				)
+			}),
+			..Default::default()
+		};
+
+		confy::store_path(&path, &config)?;
+		let loaded: Config = confy::load_path(&path)?;
+		assert_eq!(loaded.user, config.user);
+		let info = loaded.connection_info.unwrap();
+		assert_eq!(info.handle, config.connection_info.as_ref().unwrap().handle);
+		assert!(matches!(info.handle, Handle::OpenVpn { pid: 42, .. }));
+		assert_eq!(info.split_domains.len(), 1);
+		Ok(())
+	}
+
+	#[test]
+	fn test_password_not_stored() -> Result<()> {
+		let user: UserConfig = toml::from_str(&toml::to_string(&UserConfig {
+			password_store: PasswordStore::EncryptedFile,
+			..UserConfig::new("user".into())
+		})?)?;
+		assert_eq!(user.password_store, PasswordStore::EncryptedFile);
+
+		// Configs from before the password store have the password in plaintext
+		let mut legacy = toml::Value::try_from(&user)?;
+		let table = legacy.as_table_mut().unwrap();
+		table.remove("password_store");
+		table.insert("password".into(), "hunter2".into());
+		let user: UserConfig = legacy.try_into()?;
+		assert_eq!(user.legacy_password, "hunter2");
+		assert_eq!(user.password_store, PasswordStore::Keyring);
+		assert!(!toml::to_string(&user)?.contains("hunter2"));
+		Ok(())
+	}
+
+	#[test]
+	fn test_old_update_interval_ignored() -> Result<()> {
+		let mut legacy = toml::Value::try_from(UserConfig::new("user".into()))?;
+		let table = legacy.as_table_mut().unwrap();
+		table.remove("server_cache_minutes");
+		table.insert("check_update_interval".into(), 3.into());
+		let user: UserConfig = legacy.try_into()?;
+		assert_eq!(user.server_cache_minutes, 15);
+		assert!(!toml::to_string(&user)?.contains("check_update_interval"));
+		Ok(())
+	}
+
+	#[test]
+	fn test_killswitch_deserialize() {
+		#[derive(Deserialize)]
+		struct Wrapper {
+			#[serde(deserialize_with = "KillSwitch::deserialize_legacy")]
+			killswitch: KillSwitch,
+		}
+		let parse = |s: &str| toml::from_str::<Wrapper>(s).map(|w| w.killswitch);
+
+		assert_eq!(parse("killswitch = 0").unwrap(), KillSwitch::Off);
+		assert_eq!(parse("killswitch = 2").unwrap(), KillSwitch::AlwaysOn);
+		assert_eq!(parse("killswitch = \"On\"").unwrap(), KillSwitch::On);
+		assert!(parse("killswitch = 7").is_err());
+		assert!(parse("killswitch = \"sometimes\"").is_err());
+	}
 }
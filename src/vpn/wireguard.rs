@@ -0,0 +1,398 @@
+use std::{
+	convert::TryInto,
+	fs::read_to_string,
+	io::ErrorKind,
+	net::Ipv4Addr,
+	path::{Path, PathBuf},
+};
+
+use anyhow::{anyhow, bail, Context, Result};
+use askama::Template;
+use directories::ProjectDirs;
+use rand::rngs::OsRng;
+use x25519_dalek::{PublicKey, StaticSecret};
+
+use super::{
+	backend::{Handle, TunnelStatus, VpnBackend},
+	command::CommandRunner,
+	remove_if_exists,
+	split_tunnel::{complement, Cidr, SplitMode},
+	traffic,
+	util::{ConnectionInfo, ConnectionProtocol, UserConfig},
+	TunnelSettings,
+};
+use crate::{
+	constants::{WG_DEVICE, WG_FILE, WG_KEY_FILE},
+	utils::{config_path, write_atomic, LogicalServer},
+};
+
+/// Port ProtonVPN's wireguard servers listen on
+pub(crate) const WG_PORT: u16 = 51820;
+
+/// ProtonVPN's dns server inside the wireguard tunnel
+pub(crate) const WG_DNS: Ipv4Addr = Ipv4Addr::new(10, 2, 0, 1);
+
+/// Address of this end of the tunnel. ProtonVPN gives every client the same one.
+const CLIENT_ADDRESS: &str = "10.2.0.2/32";
+
+/// An x25519 keypair. Keys are written in base64, like `wg genkey` and `wg pubkey` do.
+pub struct KeyPair {
+	secret: StaticSecret,
+	public: PublicKey,
+}
+
+impl KeyPair {
+	/// A new random keypair
+	pub fn generate() -> Self {
+		Self::from_secret(StaticSecret::random_from_rng(OsRng))
+	}
+
+	/// Derives the public key from a base64 private key
+	pub fn from_base64(private_key: &str) -> Result<Self> {
+		let bytes: [u8; 32] = base64::decode(private_key.trim())
+			.context("The wireguard private key isn't valid base64")?
+			.try_into()
+			.map_err(|_| anyhow!("The wireguard private key must be 32 bytes long"))?;
+		Ok(Self::from_secret(bytes.into()))
+	}
+
+	fn from_secret(secret: StaticSecret) -> Self {
+		let public = PublicKey::from(&secret);
+		Self { secret, public }
+	}
+
+	/// Reads the private key at `path`. If there is none, a new keypair is generated and saved there, readable only by its owner.
+	pub(crate) fn load_or_generate(path: &Path) -> Result<Self> {
+		match read_to_string(path) {
+			Ok(key) => {
+				Self::from_base64(&key).with_context(|| format!("Couldn't load {}", path.display()))
+			}
+			Err(e) if e.kind() == ErrorKind::NotFound => {
+				let keys = Self::generate();
+				write_atomic(path, format!("{}\n", keys.private_key()).as_bytes())?;
+				Ok(keys)
+			}
+			Err(e) => Err(e).with_context(|| format!("Couldn't read {}", path.display())),
+		}
+	}
+
+	/// Never print or log this
+	pub(crate) fn private_key(&self) -> String {
+		base64::encode(self.secret.to_bytes())
+	}
+
+	/// ProtonVPN's servers only accept a public key that was registered with the account
+	pub fn public_key(&self) -> String {
+		base64::encode(self.public.as_bytes())
+	}
+}
+
+#[derive(Template)]
+#[template(path = "wireguard_template.j2", escape = "none")]
+struct WireGuardConfig<'a> {
+	private_key: String,
+	address: &'static str,
+	peer_public_key: &'a str,
+	endpoint: Ipv4Addr,
+	port: u16,
+	allowed_ips: Vec<String>,
+}
+
+/// Renders a wg-quick config for the first online entry of `server` that has a wireguard key.
+///
+/// Wireguard routes what its peer's AllowedIPs cover, so split tunneling is done there. Excluded networks are left out of the routed networks, and so is the server itself, or its traffic would loop through the tunnel. Ipv6 is routed into the tunnel unless only some networks are, so it can't leak.
+fn create_wireguard_config(
+	server: &LogicalServer,
+	settings: &TunnelSettings,
+	keys: &KeyPair,
+) -> Result<String> {
+	let (entry, peer_public_key) = server
+		.servers
+		.iter()
+		.filter(|s| s.status == 1)
+		.find_map(|s| Some((s.entry_ip, s.x25519_public_key.as_deref()?)))
+		.ok_or_else(|| anyhow!("{} has no wireguard server", server.name))?;
+
+	let split: Vec<Cidr> = settings.split_tunnel.iter().map(Cidr::from).collect();
+	let allowed_ips = if split.is_empty() {
+		vec!["0.0.0.0/0".into(), "::/0".into()]
+	} else if settings.split_mode == SplitMode::IncludeOnly {
+		split.iter().map(Cidr::to_string).collect()
+	} else {
+		let mut excluded = split;
+		excluded.push(entry.into());
+		let mut allowed: Vec<_> = complement(&excluded).iter().map(Cidr::to_string).collect();
+		allowed.push("::/0".into());
+		allowed
+	};
+
+	WireGuardConfig {
+		private_key: keys.private_key(),
+		address: CLIENT_ADDRESS,
+		peer_public_key,
+		endpoint: entry,
+		port: WG_PORT,
+		allowed_ips,
+	}
+	.render()
+	.context("Rendering wireguard config failed")
+}
+
+/// Brings up wireguard interfaces with wg-quick. The interface is named after the config file, see [WG_FILE](crate::constants::WG_FILE).
+pub(crate) struct WireGuard<R> {
+	config_path: PathBuf,
+	key_path: PathBuf,
+	runner: R,
+}
+
+impl<R: CommandRunner> WireGuard<R> {
+	/// Keeps the config and the private key in the app's config dir
+	pub(crate) fn new(pdir: &ProjectDirs, runner: R) -> Self {
+		Self {
+			config_path: config_path(pdir, WG_FILE),
+			key_path: config_path(pdir, WG_KEY_FILE),
+			runner,
+		}
+	}
+}
+
+impl<R: CommandRunner> VpnBackend for WireGuard<R> {
+	fn prepare(
+		&mut self,
+		server: &LogicalServer,
+		settings: &TunnelSettings,
+		user: &UserConfig,
+	) -> Result<()> {
+		let keys = KeyPair::load_or_generate(&self.key_path)?;
+		let public_key = keys.public_key();
+		if user.wireguard_public_key.as_deref() != Some(public_key.as_str()) {
+			bail!(
+				"ProtonVPN's servers only accept registered wireguard keys. Register the public key {} with your account, then confirm it with `protonvpn configure`",
+				public_key
+			);
+		}
+		let config = create_wireguard_config(server, settings, &keys)?;
+		write_atomic(&self.config_path, config.as_bytes())
+	}
+
+	fn up(&mut self, server: &LogicalServer, _settings: &TunnelSettings) -> Result<ConnectionInfo> {
+		if let Err(e) = self
+			.runner
+			.run("wg-quick", &["up", &self.config_path.to_string_lossy()])
+		{
+			remove_if_exists(&self.config_path)?;
+			return Err(e.context("Couldn't bring up the wireguard interface"));
+		}
+		Ok(ConnectionInfo::new(
+			server,
+			ConnectionProtocol::UDP,
+			WG_DEVICE,
+			Handle::WireGuard {
+				config: self.config_path.clone(),
+			},
+		))
+	}
+
+	/// Takes the interface down, if it is still up, and removes the config with the private key
+	fn down(&mut self, info: &ConnectionInfo) -> Result<()> {
+		let config = match &info.handle {
+			Handle::WireGuard { config } => config,
+			other => bail!("{:?} isn't a wireguard tunnel", other),
+		};
+		if is_up(&info.interface) {
+			self.runner
+				.run("wg-quick", &["down", &config.to_string_lossy()])
+				.context("Couldn't take down the wireguard interface")?;
+		}
+		remove_if_exists(config)
+	}
+
+	fn status(&mut self, info: &ConnectionInfo) -> Option<TunnelStatus> {
+		if !is_up(&info.interface) {
+			return None;
+		}
+		Some(TunnelStatus {
+			state: None,
+			traffic: traffic(&info.interface).ok(),
+		})
+	}
+
+	fn offered_dns(&mut self, _info: &ConnectionInfo) -> Result<Vec<Ipv4Addr>> {
+		Ok(vec![WG_DNS])
+	}
+}
+
+/// Whether `interface` exists
+pub(crate) fn is_up(interface: &str) -> bool {
+	Path::new("/sys/class/net").join(interface).exists()
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::vpn::{command::FakeRunner, firewall::tests::two_entry_server, util::Backend};
+	use tempfile::tempdir;
+
+	/// Alice's keys from the test vectors in RFC 7748, section 6.1
+	const PRIVATE_KEY: &str = "dwdtCnMYpX08FsFyUbJmRd9ML4frwJkqsXf7pR25LCo=";
+	const PUBLIC_KEY: &str = "hSDwCYkwp1R0i33ctD73Wg2/Og0mOBr066SpjqqbTmo=";
+
+	fn wireguard_server() -> LogicalServer {
+		let mut server = two_entry_server();
+		server.servers[0].status = 0;
+		for s in &mut server.servers {
+			s.x25519_public_key = Some(PUBLIC_KEY.into());
+		}
+		server
+	}
+
+	fn settings(split_tunnel: &[&str], split_mode: SplitMode) -> TunnelSettings {
+		TunnelSettings {
+			backend: Backend::WireGuard,
+			protocol: ConnectionProtocol::UDP,
+			ports: vec![WG_PORT],
+			ipv6_disabled: true,
+			split_tunnel: split_tunnel
+				.iter()
+				.map(|s| s.parse::<Cidr>().unwrap().into())
+				.collect(),
+			split_mode,
+		}
+	}
+
+	#[test]
+	fn test_keys() -> Result<()> {
+		let keys = KeyPair::from_base64(PRIVATE_KEY)?;
+		assert_eq!(keys.private_key(), PRIVATE_KEY);
+		assert_eq!(keys.public_key(), PUBLIC_KEY);
+
+		assert!(KeyPair::from_base64("not base64!").is_err());
+		assert!(KeyPair::from_base64("c2hvcnQ=").is_err());
+
+		let generated = KeyPair::generate();
+		assert_ne!(generated.private_key(), KeyPair::generate().private_key());
+		let reloaded = KeyPair::from_base64(&generated.private_key())?;
+		assert_eq!(reloaded.public_key(), generated.public_key());
+		Ok(())
+	}
+
+	#[test]
+	fn test_load_or_generate() -> Result<()> {
+		use std::os::unix::fs::PermissionsExt;
+
+		let dir = tempdir()?;
+		let path = dir.path().join("wireguard.key");
+		let keys = KeyPair::load_or_generate(&path)?;
+		assert_eq!(path.metadata()?.permissions().mode() & 0o777, 0o600);
+		assert_eq!(
+			KeyPair::load_or_generate(&path)?.public_key(),
+			keys.public_key()
+		);
+		Ok(())
+	}
+
+	#[test]
+	fn test_create_wireguard_config() -> Result<()> {
+		let keys = KeyPair::from_base64(PRIVATE_KEY)?;
+		let config = create_wireguard_config(
+			&wireguard_server(),
+			&settings(&[], SplitMode::Exclude),
+			&keys,
+		)?;
+		let expected = format!(
+			"[Interface]
+PrivateKey = {}
+Address = 10.2.0.2/32
+
+[Peer]
+PublicKey = {}
+AllowedIPs = 0.0.0.0/0, ::/0
+Endpoint = 185.159.157.2:51820
+PersistentKeepalive = 25",
+			PRIVATE_KEY, PUBLIC_KEY
+		);
+		assert_eq!(config, expected);
+
+		let config = create_wireguard_config(
+			&wireguard_server(),
+			&settings(&["10.0.0.0/8", "192.168.1.0/24"], SplitMode::IncludeOnly),
+			&keys,
+		)?;
+		assert!(config.contains(
+			"AllowedIPs = 10.0.0.0/8, 192.168.1.0/24
+"
+		));
+
+		let config = create_wireguard_config(
+			&wireguard_server(),
+			&settings(&["0.0.0.0/1"], SplitMode::Exclude),
+			&keys,
+		)?;
+		// The server's own address stays outside the tunnel
+		assert!(config.contains("AllowedIPs = 128.0.0.0/3, 160.0.0.0/4,"));
+		assert!(config.contains(" 185.159.157.0/31, 185.159.157.3/32, 185.159.157.4/30,"));
+		assert!(config.contains(" 192.0.0.0/2, ::/0\n"));
+		assert!(config.ends_with(
+			", ::/0
+Endpoint = 185.159.157.2:51820
+PersistentKeepalive = 25"
+		));
+
+		let err = create_wireguard_config(
+			&two_entry_server(),
+			&settings(&[], SplitMode::Exclude),
+			&keys,
+		)
+		.unwrap_err();
+		assert_eq!(err.to_string(), "CH#1 has no wireguard server");
+		Ok(())
+	}
+
+	#[test]
+	fn test_up_down() -> Result<()> {
+		let dir = tempdir()?;
+		let mut wireguard = WireGuard {
+			config_path: dir.path().join("protonwg0.conf"),
+			key_path: dir.path().join("wireguard.key"),
+			runner: FakeRunner::default(),
+		};
+		let mut user = UserConfig::new("user".into());
+		let settings = settings(&[], SplitMode::Exclude);
+		// The generated key isn't registered yet
+		let err = wireguard
+			.prepare(&wireguard_server(), &settings, &user)
+			.unwrap_err();
+		let keys = KeyPair::load_or_generate(&wireguard.key_path)?;
+		assert!(err.to_string().contains(&keys.public_key()), "{}", err);
+		assert!(!wireguard.config_path.exists());
+
+		user.wireguard_public_key = Some(keys.public_key());
+		wireguard.prepare(&wireguard_server(), &settings, &user)?;
+		assert!(read_to_string(&wireguard.config_path)?.contains(&keys.private_key()));
+
+		let info = wireguard.up(&wireguard_server(), &settings)?;
+		assert_eq!(
+			info.handle,
+			Handle::WireGuard {
+				config: wireguard.config_path.clone()
+			}
+		);
+		assert_eq!(info.transport(), "WireGuard");
+		assert_eq!(
+			wireguard.runner.commands,
+			[format!("wg-quick up {}", wireguard.config_path.display())]
+		);
+
+		// The interface was never really created, so there is nothing to take down
+		assert_eq!(wireguard.status(&info), None);
+		wireguard.down(&info)?;
+		assert!(!wireguard.config_path.exists());
+		assert_eq!(wireguard.runner.commands.len(), 1);
+
+		wireguard.runner.failing.push("wg-quick");
+		wireguard.prepare(&wireguard_server(), &settings, &user)?;
+		assert!(wireguard.up(&wireguard_server(), &settings).is_err());
+		assert!(!wireguard.config_path.exists());
+		Ok(())
+	}
+}